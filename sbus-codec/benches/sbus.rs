@@ -0,0 +1,46 @@
+//! Compares the table-driven SBUS codec against the hand-written shifts it
+//! replaced. Run with `cargo bench -p sbus-codec`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+#[path = "../src/legacy.rs"]
+mod legacy;
+
+use legacy::{legacy_decode, legacy_encode};
+
+// A frame like the ones we send the car
+const CHANNELS: [u16; 16] = [
+    1500, 1024, 300, 1024, 1807, 1807, 240, 240, 1024, 1024, 1024, 1024, 1024, 1024, 1024, 1024,
+];
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.bench_function("legacy", |b| b.iter(|| legacy_encode(black_box(CHANNELS))));
+    group.bench_function("codec", |b| {
+        b.iter(|| {
+            let mut frame = [0; sbus_codec::FRAME_LEN];
+            sbus_codec::encode_into(
+                black_box(&CHANNELS),
+                Default::default(),
+                sbus_codec::FOOTER,
+                &mut frame,
+            );
+            frame
+        })
+    });
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let frame = legacy_encode(CHANNELS);
+
+    let mut group = c.benchmark_group("decode");
+    group.bench_function("legacy", |b| b.iter(|| legacy_decode(black_box(&frame))));
+    group.bench_function("codec", |b| {
+        b.iter(|| sbus_codec::decode(black_box(&frame)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
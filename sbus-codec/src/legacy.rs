@@ -0,0 +1,63 @@
+//! The hand-written SBUS shifts the codec replaced, kept as the baseline the
+//! codec is benchmarked and tested against.
+
+/// The encoder as it was before the codec, kept as the baseline.
+pub fn legacy_encode(channels: [u16; 16]) -> [u8; 25] {
+    let mut buf = [0u8; 25];
+    buf[0] = 0x0F;
+    buf[1] = (channels[0] & 0x07FF) as u8;
+    buf[2] = (((channels[0] & 0x07FF) >> 8) | ((channels[1] & 0x07FF) << 3)) as u8;
+    buf[3] = (((channels[1] & 0x07FF) >> 5) | ((channels[2] & 0x07FF) << 6)) as u8;
+    buf[4] = ((channels[2] & 0x07FF) >> 2) as u8;
+    buf[5] = (((channels[2] & 0x07FF) >> 10) | ((channels[3] & 0x07FF) << 1)) as u8;
+    buf[6] = (((channels[3] & 0x07FF) >> 7) | ((channels[4] & 0x07FF) << 4)) as u8;
+    buf[7] = (((channels[4] & 0x07FF) >> 4) | ((channels[5] & 0x07FF) << 7)) as u8;
+    buf[8] = ((channels[5] & 0x07FF) >> 1) as u8;
+    buf[9] = (((channels[5] & 0x07FF) >> 9) | ((channels[6] & 0x07FF) << 2)) as u8;
+    buf[10] = (((channels[6] & 0x07FF) >> 6) | ((channels[7] & 0x07FF) << 5)) as u8;
+    buf[11] = ((channels[7] & 0x07FF) >> 3) as u8;
+    buf[12] = (channels[8] & 0x07FF) as u8;
+    buf[13] = (((channels[8] & 0x07FF) >> 8) | ((channels[9] & 0x07FF) << 3)) as u8;
+    buf[14] = (((channels[9] & 0x07FF) >> 5) | ((channels[10] & 0x07FF) << 6)) as u8;
+    buf[15] = ((channels[10] & 0x07FF) >> 2) as u8;
+    buf[16] = (((channels[10] & 0x07FF) >> 10) | ((channels[11] & 0x07FF) << 1)) as u8;
+    buf[17] = (((channels[11] & 0x07FF) >> 7) | ((channels[12] & 0x07FF) << 4)) as u8;
+    buf[18] = (((channels[12] & 0x07FF) >> 4) | ((channels[13] & 0x07FF) << 7)) as u8;
+    buf[19] = ((channels[13] & 0x07FF) >> 1) as u8;
+    buf[20] = (((channels[13] & 0x07FF) >> 9) | ((channels[14] & 0x07FF) << 2)) as u8;
+    buf[21] = (((channels[14] & 0x07FF) >> 6) | ((channels[15] & 0x07FF) << 5)) as u8;
+    buf[22] = ((channels[15] & 0x07FF) >> 3) as u8;
+    buf[23] = 0x00;
+    buf[24] = 0x00;
+
+    buf
+}
+
+/// The parser's channel unpacking as it was before the codec.
+pub fn legacy_decode(frame: &[u8; 25]) -> [u16; 16] {
+    let mut data_bytes: [u16; 23] = [0; 23];
+    for (byte, &b) in data_bytes.iter_mut().zip(frame.iter()) {
+        *byte = b as u16;
+    }
+
+    let mut channels: [u16; 16] = [0; 16];
+    channels[0] = (data_bytes[1] | (data_bytes[2] << 8)) & 0x07FF;
+    channels[1] = ((data_bytes[2] >> 3) | (data_bytes[3] << 5)) & 0x07FF;
+    channels[2] = ((data_bytes[3] >> 6) | (data_bytes[4] << 2) | (data_bytes[5] << 10)) & 0x07FF;
+    channels[3] = ((data_bytes[5] >> 1) | (data_bytes[6] << 7)) & 0x07FF;
+    channels[4] = ((data_bytes[6] >> 4) | (data_bytes[7] << 4)) & 0x07FF;
+    channels[5] = ((data_bytes[7] >> 7) | (data_bytes[8] << 1) | (data_bytes[9] << 9)) & 0x07FF;
+    channels[6] = ((data_bytes[9] >> 2) | (data_bytes[10] << 6)) & 0x07FF;
+    channels[7] = ((data_bytes[10] >> 5) | (data_bytes[11] << 3)) & 0x07FF;
+    channels[8] = (data_bytes[12] | (data_bytes[13] << 8)) & 0x07FF;
+    channels[9] = ((data_bytes[13] >> 3) | (data_bytes[14] << 5)) & 0x07FF;
+    channels[10] =
+        ((data_bytes[14] >> 6) | (data_bytes[15] << 2) | (data_bytes[16] << 10)) & 0x07FF;
+    channels[11] = ((data_bytes[16] >> 1) | (data_bytes[17] << 7)) & 0x07FF;
+    channels[12] = ((data_bytes[17] >> 4) | (data_bytes[18] << 4)) & 0x07FF;
+    channels[13] = ((data_bytes[18] >> 7) | (data_bytes[19] << 1) | (data_bytes[20] << 9)) & 0x07FF;
+    channels[14] = ((data_bytes[20] >> 2) | (data_bytes[21] << 6)) & 0x07FF;
+    channels[15] = ((data_bytes[21] >> 5) | (data_bytes[22] << 3)) & 0x07FF;
+
+    channels
+}
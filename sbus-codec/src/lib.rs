@@ -0,0 +1,339 @@
+//! SBUS frame encoding and decoding, shared by the car link, the proxy, the
+//! monitor and the benchmarks. Never allocates, it runs for every frame, and
+//! only needs `core`.
+
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+#[cfg(test)]
+mod legacy;
+
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+
+pub const FRAME_LEN: usize = 25;
+pub const HEADER: u8 = 0x0F;
+pub const FOOTER: u8 = 0x00;
+/// SBUS2 cycles through these footers, each one telling the sensors which
+/// group of telemetry slots follows the frame.
+pub const SBUS2_FOOTERS: [u8; 4] = [0x04, 0x14, 0x24, 0x34];
+
+const CHANNEL_BITS: usize = 11;
+const CHANNEL_MASK: u32 = (1 << CHANNEL_BITS) - 1;
+const FLAGS_BYTE: usize = 23;
+// The top four bits of the flags byte are always clear
+const FLAGS_UNUSED: u8 = 0xF0;
+
+/// Where each channel's 11 bits start: the first data byte they touch, and
+/// how far into it. The channels are packed back to back, least significant
+/// bit first, from byte 1.
+const CHANNEL_POSITIONS: [(usize, u32); 16] = channel_positions();
+
+const fn channel_positions() -> [(usize, u32); 16] {
+    let mut positions = [(0, 0); 16];
+    let mut channel = 0;
+    while channel < 16 {
+        let bit = channel * CHANNEL_BITS;
+        positions[channel] = (1 + bit / 8, (bit % 8) as u32);
+        channel += 1;
+    }
+    positions
+}
+
+/// The flags byte at the end of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Flags {
+    /// Digital channels 17 and 18.
+    pub d1: bool,
+    pub d2: bool,
+    pub frame_lost: bool,
+    pub failsafe: bool,
+}
+
+impl Flags {
+    pub fn to_byte(self) -> u8 {
+        u8::from(self.d1)
+            | u8::from(self.d2) << 1
+            | u8::from(self.frame_lost) << 2
+            | u8::from(self.failsafe) << 3
+    }
+
+    pub fn from_byte(byte: u8) -> Flags {
+        Flags {
+            d1: byte & 1 != 0,
+            d2: byte & 1 << 1 != 0,
+            frame_lost: byte & 1 << 2 != 0,
+            failsafe: byte & 1 << 3 != 0,
+        }
+    }
+}
+
+/// The flavours of SBUS receivers and servos expect. The frame is the same in
+/// all of them, only the line speed, how often frames go out and the footer
+/// change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Variant {
+    /// 100000 baud, a frame every 14ms.
+    #[default]
+    Standard,
+    /// Standard SBUS in high speed mode, a frame every 7ms.
+    HighSpeed,
+    /// Fast SBUS: 200000 baud, a frame every 7ms.
+    Fast,
+    /// Futaba SBUS2: standard timing, with footers leaving room for
+    /// telemetry slots after each frame.
+    Sbus2,
+}
+
+/// A variant name `Variant::from_str` doesn't know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownVariant;
+
+impl fmt::Display for UnknownVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown SBUS variant")
+    }
+}
+
+impl FromStr for Variant {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<Variant, UnknownVariant> {
+        match s {
+            "standard" => Ok(Variant::Standard),
+            "high-speed" => Ok(Variant::HighSpeed),
+            "fast" => Ok(Variant::Fast),
+            "sbus2" => Ok(Variant::Sbus2),
+            _ => Err(UnknownVariant),
+        }
+    }
+}
+
+impl Variant {
+    pub fn baud_rate(self) -> u32 {
+        match self {
+            Variant::Fast => 200_000,
+            Variant::Standard | Variant::HighSpeed | Variant::Sbus2 => 100_000,
+        }
+    }
+
+    /// Time between the starts of two frames.
+    pub fn frame_interval(self) -> Duration {
+        match self {
+            Variant::HighSpeed | Variant::Fast => Duration::from_millis(7),
+            Variant::Standard | Variant::Sbus2 => Duration::from_millis(14),
+        }
+    }
+
+    /// The footer of the `frame`th frame sent.
+    pub fn footer(self, frame: usize) -> u8 {
+        match self {
+            Variant::Sbus2 => SBUS2_FOOTERS[frame % SBUS2_FOOTERS.len()],
+            Variant::Standard | Variant::HighSpeed | Variant::Fast => FOOTER,
+        }
+    }
+}
+
+fn is_footer(byte: u8) -> bool {
+    byte == FOOTER || SBUS2_FOOTERS.contains(&byte)
+}
+
+/// Writes a whole frame into `frame`, ending in `footer`. Channel values are
+/// 11 bits, anything above that is dropped.
+pub fn encode_into(channels: &[u16; 16], flags: Flags, footer: u8, frame: &mut [u8; FRAME_LEN]) {
+    *frame = [0; FRAME_LEN];
+    frame[0] = HEADER;
+
+    for (&value, &(byte, shift)) in channels.iter().zip(CHANNEL_POSITIONS.iter()) {
+        // 11 bits at any shift fit in three bytes
+        let bits = (u32::from(value) & CHANNEL_MASK) << shift;
+        frame[byte] |= bits as u8;
+        frame[byte + 1] |= (bits >> 8) as u8;
+        if byte + 2 < FLAGS_BYTE {
+            frame[byte + 2] |= (bits >> 16) as u8;
+        }
+    }
+
+    frame[FLAGS_BYTE] = flags.to_byte();
+    frame[FRAME_LEN - 1] = footer;
+}
+
+/// Whether `frame` looks like an SBUS or SBUS2 frame: right header, footer
+/// and unused flag bits.
+pub fn is_valid(frame: &[u8; FRAME_LEN]) -> bool {
+    frame[0] == HEADER && is_footer(frame[FRAME_LEN - 1]) && frame[FLAGS_BYTE] & FLAGS_UNUSED == 0
+}
+
+/// Reads the channels and flags out of `frame`, if it is valid.
+pub fn decode(frame: &[u8; FRAME_LEN]) -> Option<([u16; 16], Flags)> {
+    if !is_valid(frame) {
+        return None;
+    }
+
+    let mut channels = [0; 16];
+    for (channel, &(byte, shift)) in channels.iter_mut().zip(CHANNEL_POSITIONS.iter()) {
+        let high = if byte + 2 < FLAGS_BYTE {
+            u32::from(frame[byte + 2]) << 16
+        } else {
+            0
+        };
+        let bits = u32::from(frame[byte]) | u32::from(frame[byte + 1]) << 8 | high;
+        *channel = ((bits >> shift) & CHANNEL_MASK) as u16;
+    }
+
+    Some((channels, Flags::from_byte(frame[FLAGS_BYTE])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy::{legacy_decode, legacy_encode};
+    use std::vec;
+    use std::vec::Vec;
+
+    const ALL: [Variant; 4] = [
+        Variant::Standard,
+        Variant::HighSpeed,
+        Variant::Fast,
+        Variant::Sbus2,
+    ];
+
+    fn encode(channels: &[u16; 16], flags: Flags) -> [u8; FRAME_LEN] {
+        let mut frame = [0; FRAME_LEN];
+        encode_into(channels, flags, FOOTER, &mut frame);
+        frame
+    }
+
+    /// Channel values from a fixed xorshift, the same every run.
+    fn random_channels(count: usize) -> Vec<[u16; 16]> {
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state & u64::from(CHANNEL_MASK)) as u16
+        };
+        (0..count)
+            .map(|_| core::array::from_fn(|_| next()))
+            .collect()
+    }
+
+    fn edge_channels() -> Vec<[u16; 16]> {
+        let mut frames = vec![[0; 16], [2047; 16], [1024; 16]];
+        // Each channel alone at the ends, to catch bits leaking next door
+        for channel in 0..16 {
+            for value in [0, 1, 1024, 2046, 2047] {
+                let mut channels = [0; 16];
+                channels[channel] = value;
+                frames.push(channels);
+                let mut channels = [2047; 16];
+                channels[channel] = 2047 - value;
+                frames.push(channels);
+            }
+        }
+        // Alternating, so neighbours disagree on every bit
+        frames.push(core::array::from_fn(
+            |i| if i % 2 == 0 { 0x555 } else { 0x2AA },
+        ));
+        frames
+    }
+
+    #[test]
+    fn encodes_like_the_legacy_encoder() {
+        for channels in random_channels(1000).into_iter().chain(edge_channels()) {
+            assert_eq!(
+                encode(&channels, Flags::default()),
+                legacy_encode(channels),
+                "{:?}",
+                channels
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_like_the_legacy_decoder() {
+        for channels in random_channels(1000).into_iter().chain(edge_channels()) {
+            let frame = legacy_encode(channels);
+            assert_eq!(legacy_decode(&frame), channels);
+            assert_eq!(decode(&frame), Some((channels, Flags::default())));
+        }
+    }
+
+    #[test]
+    fn values_past_11_bits_are_dropped() {
+        let frame = encode(&[0xFFFF; 16], Flags::default());
+        assert_eq!(frame, legacy_encode([0xFFFF; 16]));
+        assert_eq!(decode(&frame).unwrap().0, [2047; 16]);
+    }
+
+    // The legacy parser only ever read d1 correctly, so the flags are
+    // checked against the SBUS layout instead: d1, d2, frame lost, failsafe
+    // from the lowest bit
+    #[test]
+    fn flags_sit_in_their_bits() {
+        let failsafe = Flags {
+            failsafe: true,
+            ..Flags::default()
+        };
+        let frame_lost = Flags {
+            frame_lost: true,
+            ..Flags::default()
+        };
+        assert_eq!(encode(&[0; 16], failsafe)[FLAGS_BYTE], 0x08);
+        assert_eq!(encode(&[0; 16], frame_lost)[FLAGS_BYTE], 0x04);
+
+        for byte in 0..16 {
+            let flags = Flags::from_byte(byte);
+            assert_eq!(flags.to_byte(), byte);
+            for channels in edge_channels() {
+                let frame = encode(&channels, flags);
+                // Flags never touch the channels
+                assert_eq!(frame[..FLAGS_BYTE], legacy_encode(channels)[..FLAGS_BYTE]);
+                assert_eq!(decode(&frame), Some((channels, flags)));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_framing() {
+        let good = encode(&[1024; 16], Flags::default());
+        assert!(is_valid(&good));
+
+        let mut bad_header = good;
+        bad_header[0] = 0x0E;
+        let mut bad_footer = good;
+        bad_footer[FRAME_LEN - 1] = 0x44;
+        let mut unused_flags = good;
+        unused_flags[FLAGS_BYTE] |= 0x10;
+        for frame in [bad_header, bad_footer, unused_flags] {
+            assert_eq!(decode(&frame), None);
+        }
+    }
+
+    #[test]
+    fn line_speed_and_cadence() {
+        let timings: Vec<(u32, u64)> = ALL
+            .iter()
+            .map(|variant| {
+                let interval = variant.frame_interval().as_millis() as u64;
+                (variant.baud_rate(), interval)
+            })
+            .collect();
+        assert_eq!(
+            timings,
+            [(100_000, 14), (100_000, 7), (200_000, 7), (100_000, 14)]
+        );
+    }
+
+    #[test]
+    fn variants_parse_by_name() {
+        let names = ["standard", "high-speed", "fast", "sbus2"];
+        for (name, variant) in names.iter().zip(ALL) {
+            assert_eq!(name.parse(), Ok(variant));
+        }
+        assert!("sbus3".parse::<Variant>().is_err());
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use crate::drive_mode::ModeEvent;
+use crate::latency::Percentiles;
+use crate::link::LinkStats;
+use crate::state_manager::{update_shared, Slot, StateManager};
+
+// How often the WebSocket stream checks for a new state to send, and how
+// long it waits on the client in between
+const STREAM_INTERVAL: Duration = Duration::from_millis(100);
+// A client that doesn't finish its request in this long is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A page for the pit crew: the state as it streams in, and the controls.
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta name="viewport" content="width=device-width"><title>glorb-control</title></head>
+<body style="font-family: monospace">
+<button onclick="control('estop')" style="background: red; color: white; font-size: 2em">E-STOP</button>
+<button onclick="control('disarm')">Disarm</button>
+<button onclick="control('arm?slot=left')">Arm left</button>
+<button onclick="control('arm?slot=right')">Arm right</button>
+<pre id="state">Connecting...</pre>
+<script>
+let token = null;
+function control(action) {
+  token = token || prompt("API token");
+  fetch("/" + action, { method: "POST", headers: { Authorization: "Bearer " + token } })
+    .then(r => r.json()).then(r => { if (r.error) alert(r.error); });
+}
+function connect() {
+  const ws = new WebSocket("ws://" + location.host + "/ws");
+  ws.onmessage = e => document.getElementById("state").textContent = JSON.stringify(JSON.parse(e.data), null, 2);
+  ws.onclose = () => setTimeout(connect, 1000);
+}
+connect();
+</script>
+</body>
+</html>
+"#;
+
+/// Everything the API serves about the car and the controllers.
+fn state_json(state: &StateManager, stats: &LinkStats) -> Value {
+    let controller = |slot: Slot| {
+        json!({
+            "mode": format!("{:?}", state.slot(slot).mode),
+            "in_control": state.in_control(slot),
+            "throttle": state.slot(slot).throttle,
+        })
+    };
+    let telemetry = &state.telemetry;
+
+    json!({
+        "policy": format!("{:?}", state.arbiter.policy()),
+        "owner": state.arbiter.owner(),
+        "pending_handover": state.arbiter.pending_request(),
+        "profile": format!("{:?}", state.profile),
+        "link_ok": state.link_ok,
+        "controllers": {
+            "left": controller(Slot::Left),
+            "right": controller(Slot::Right),
+        },
+        "telemetry": {
+            "voltage": telemetry.voltage,
+            "current": telemetry.current,
+            "remaining": telemetry.remaining,
+            "rssi": telemetry.rssi,
+            "link_quality": telemetry.link_quality,
+        },
+        "battery": format!("{:?}", state.battery.level()),
+        "link": link_json(stats),
+    })
+}
+
+fn link_json(stats: &LinkStats) -> Value {
+    let latency = &stats.latency;
+    json!({
+        "frames": stats.frames,
+        "failures": stats.failures,
+        "command": stats.command,
+        "channels": stats.channels,
+        "error": stats.error,
+        "latency": {
+            "mapping": percentiles_json(latency.mapping),
+            "queue": percentiles_json(latency.queue),
+            "write": percentiles_json(latency.write),
+            "total": percentiles_json(latency.total),
+        },
+    })
+}
+
+/// In milliseconds, or null if nothing was measured.
+fn percentiles_json(percentiles: Option<Percentiles>) -> Value {
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    match percentiles {
+        Some(percentiles) => json!({
+            "count": percentiles.count,
+            "p50_ms": millis(percentiles.p50),
+            "p95_ms": millis(percentiles.p95),
+            "p99_ms": millis(percentiles.p99),
+        }),
+        None => Value::Null,
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    /// Reads the request line and headers. Bodies are never needed, so
+    /// they are left unread.
+    fn read(reader: &mut impl BufRead) -> io::Result<Request> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad request line",
+            ));
+        };
+        let method = method.to_owned();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = path.to_owned();
+        let query = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+
+        let mut headers = HashMap::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+            }
+        }
+
+        Ok(Request {
+            method,
+            path,
+            query,
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Headers: Authorization\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+fn respond_json(stream: &mut TcpStream, status: &str, body: Value) -> io::Result<()> {
+    respond(stream, status, "application/json", &body.to_string())
+}
+
+/// Compares without stopping at the first difference, so the time taken
+/// doesn't give the token away.
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Runs a control request, returning the status and the body to answer with.
+fn control(
+    request: &Request,
+    token: Option<&str>,
+    state_store: &ArcSwap<StateManager>,
+) -> (&'static str, Value) {
+    let Some(token) = token else {
+        return (
+            "403 Forbidden",
+            json!({ "error": "control is off, start with --api-token" }),
+        );
+    };
+    let given = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !given.is_some_and(|given| token_matches(given, token)) {
+        return ("401 Unauthorized", json!({ "error": "wrong token" }));
+    }
+
+    let (slots, event) = match request.path.as_str() {
+        "/estop" => (vec![Slot::Left], ModeEvent::EStop),
+        "/disarm" => (vec![Slot::Left, Slot::Right], ModeEvent::Disarm),
+        "/arm" => match request.query.get("slot").map(String::as_str) {
+            Some("left") => (vec![Slot::Left], ModeEvent::Arm),
+            Some("right") => (vec![Slot::Right], ModeEvent::Arm),
+            _ => {
+                return (
+                    "400 Bad Request",
+                    json!({ "error": "arm needs slot=left or slot=right" }),
+                )
+            }
+        },
+        _ => return ("404 Not Found", json!({ "error": "no such action" })),
+    };
+
+    info!("API: {:?} requested", event);
+    let mut rejected = None;
+    let mut changed = false;
+    update_shared(state_store, |state| {
+        rejected = None;
+        let before = (state.l.mode, state.r.mode);
+        for &slot in &slots {
+            // Checked here as well as in the transition to tell the client why
+            let (mode, throttle) = (state.slot(slot).mode, state.slot(slot).throttle);
+            if let Err(e) = mode.next(event, throttle) {
+                rejected = Some(e.to_string());
+            }
+            state.transition(slot, event, throttle);
+        }
+        changed = (state.l.mode, state.r.mode) != before;
+        state.arbitrate();
+    });
+
+    // A disarm that reached one of the controllers did its job
+    match rejected {
+        Some(reason) if !changed => ("409 Conflict", json!({ "error": reason })),
+        _ => ("200 OK", json!({ "ok": true })),
+    }
+}
+
+/// Streams the state to a WebSocket client whenever it changes, until the
+/// client closes the stream or goes away. Pings are answered in between.
+fn stream_state(
+    mut stream: TcpStream,
+    request: &Request,
+    state_store: &ArcSwap<StateManager>,
+    link_stats: &ArcSwap<LinkStats>,
+) -> io::Result<()> {
+    let Some(key) = request.header("sec-websocket-key") else {
+        return respond_json(
+            &mut stream,
+            "400 Bad Request",
+            json!({ "error": "not a WebSocket request" }),
+        );
+    };
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    )?;
+    stream.set_read_timeout(Some(STREAM_INTERVAL))?;
+
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+    let mut last_sent = String::new();
+    loop {
+        let state = state_json(&state_store.load(), &link_stats.load()).to_string();
+        if state != last_sent {
+            if let Err(e) = socket.send(Message::Text(state.clone())) {
+                debug!("API stream closed: {}", e);
+                return Ok(());
+            }
+            last_sent = state;
+        }
+
+        // Reading queues the replies to pings and closes, and sends them
+        match socket.read() {
+            Ok(Message::Close(_)) => {
+                debug!("API stream closed by the client");
+                // Sending the reply to the close can only fail if it's gone
+                let _ = socket.flush();
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => {
+                debug!("API stream closed: {}", e);
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn handle(
+    stream: TcpStream,
+    token: Option<&str>,
+    state_store: &ArcSwap<StateManager>,
+    link_stats: &ArcSwap<LinkStats>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = Request::read(&mut BufReader::new(stream.try_clone()?))?;
+    debug!("API: {} {}", request.method, request.path);
+    let mut stream = stream;
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => respond(&mut stream, "200 OK", "text/html", PAGE),
+        ("GET", "/state") => respond_json(
+            &mut stream,
+            "200 OK",
+            state_json(&state_store.load(), &link_stats.load()),
+        ),
+        ("GET", "/channels") => respond_json(&mut stream, "200 OK", link_json(&link_stats.load())),
+        ("GET", "/ws") => stream_state(stream, &request, state_store, link_stats),
+        // Browsers check before sending the token from another page
+        ("OPTIONS", _) => respond(&mut stream, "204 No Content", "text/plain", ""),
+        ("POST", _) => {
+            let (status, body) = control(&request, token, state_store);
+            respond_json(&mut stream, status, body)
+        }
+        _ => respond_json(
+            &mut stream,
+            "404 Not Found",
+            json!({ "error": "not found" }),
+        ),
+    }
+}
+
+/// Serves the state as JSON on `address`, streams it over a WebSocket, and
+/// takes arm, disarm and emergency stop requests carrying `token`. Without a
+/// token only the state is served.
+pub fn start_api(
+    address: &str,
+    token: Option<String>,
+    state_store: Arc<ArcSwap<StateManager>>,
+    link_stats: Arc<ArcSwap<LinkStats>>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("API listening on http://{}", listener.local_addr()?);
+    let token: Option<Arc<str>> = token.map(Arc::from);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("API connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            let token = token.clone();
+            let state_store = state_store.clone();
+            let link_stats = link_stats.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle(stream, token.as_deref(), &state_store, &link_stats) {
+                    debug!("API request failed: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitration::Policy;
+    use crate::drive_mode::DriveMode;
+    use std::sync::mpsc;
+
+    const TOKEN: &str = "let me in";
+
+    fn post(path: &str) -> Request {
+        let request = format!(
+            "POST {} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            path, TOKEN
+        );
+        Request::read(&mut request.as_bytes()).unwrap()
+    }
+
+    fn state(left: DriveMode, right: DriveMode) -> ArcSwap<StateManager> {
+        let mut state = StateManager::new(Policy::default());
+        state.l.mode = left;
+        state.r.mode = right;
+        ArcSwap::from_pointee(state)
+    }
+
+    #[test]
+    fn disarm_succeeds_if_either_controller_disarms() {
+        let store = state(DriveMode::Driving, DriveMode::Failsafe);
+        let (status, _) = control(&post("/disarm"), Some(TOKEN), &store);
+        assert_eq!(status, "200 OK");
+        assert_eq!(store.load().l.mode, DriveMode::Disarmed);
+        assert_eq!(store.load().r.mode, DriveMode::Failsafe);
+    }
+
+    #[test]
+    fn conflict_only_when_nothing_changed() {
+        let store = state(DriveMode::Failsafe, DriveMode::Failsafe);
+        let (status, body) = control(&post("/disarm"), Some(TOKEN), &store);
+        assert_eq!(status, "409 Conflict");
+        assert!(body["error"].is_string());
+
+        let store = state(DriveMode::EStop, DriveMode::EStop);
+        let (status, _) = control(&post("/arm?slot=left"), Some(TOKEN), &store);
+        assert_eq!(status, "409 Conflict");
+    }
+
+    #[test]
+    fn stream_answers_pings_and_ends_on_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let store = state(DriveMode::Driving, DriveMode::Disarmed);
+            let stats = ArcSwap::from_pointee(LinkStats::default());
+            done_tx.send(handle(stream, None, &store, &stats)).unwrap();
+        });
+
+        // So the test fails rather than hangs if nothing comes back
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let url = format!("ws://{}/ws", address);
+        let (mut client, _) = tungstenite::client(url, stream).unwrap();
+        let state: Value = match client.read().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected the state, got {:?}", other),
+        };
+        assert!(state.is_object());
+
+        client
+            .send(Message::Ping(b"still there?".to_vec()))
+            .unwrap();
+        assert_eq!(
+            client.read().unwrap(),
+            Message::Pong(b"still there?".to_vec())
+        );
+
+        client.close(None).unwrap();
+        assert!(matches!(client.read().unwrap(), Message::Close(_)));
+        let result = done_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn control_needs_the_token() {
+        let store = state(DriveMode::Driving, DriveMode::Disarmed);
+        let mut request = post("/estop");
+        request
+            .headers
+            .insert("authorization".to_owned(), "Bearer nope".to_owned());
+        assert_eq!(control(&request, Some(TOKEN), &store).0, "401 Unauthorized");
+        assert_eq!(control(&post("/estop"), None, &store).0, "403 Forbidden");
+        assert_eq!(store.load().l.mode, DriveMode::Driving);
+    }
+}
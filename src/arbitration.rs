@@ -0,0 +1,277 @@
+use std::str::FromStr;
+
+use crate::state_manager::Slot;
+
+/// How control of the car is shared between the two controllers when both
+/// are armed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// The controller in this slot takes over whenever it is armed. The other
+    /// one drives the rest of the time.
+    InstructorOverride(Slot),
+    /// Whoever armed first drives until they disarm.
+    #[default]
+    FirstArmedWins,
+    /// Like `FirstArmedWins`, but the other controller can ask for control
+    /// and gets it once whoever is driving accepts.
+    Handover,
+    /// Trainer mode: the other controller is the student and drives, the
+    /// instructor in this slot drives for as long as they hold the takeover
+    /// buttons. Until the instructor arms, the car keeps the student's mode.
+    Trainer(Slot),
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Policy, String> {
+        match s {
+            "instructor-left" => Ok(Policy::InstructorOverride(Slot::Left)),
+            "instructor-right" => Ok(Policy::InstructorOverride(Slot::Right)),
+            "first-armed" => Ok(Policy::FirstArmedWins),
+            "handover" => Ok(Policy::Handover),
+            "trainer-left" => Ok(Policy::Trainer(Slot::Left)),
+            "trainer-right" => Ok(Policy::Trainer(Slot::Right)),
+            _ => Err(format!("Unknown policy: {}", s)),
+        }
+    }
+}
+
+/// Decides which controller is driving. It only knows which slots are armed,
+/// holding takeover or pressed handover, nothing about the controllers
+/// themselves.
+#[derive(Debug, Clone)]
+pub struct Arbiter {
+    policy: Policy,
+    owner: Option<Slot>,
+    /// The controller that asked to take over, under `Policy::Handover`.
+    request: Option<Slot>,
+}
+
+impl Arbiter {
+    pub fn new(policy: Policy) -> Arbiter {
+        Arbiter {
+            policy,
+            owner: None,
+            request: None,
+        }
+    }
+
+    /// How the driver is chosen when both controllers are armed.
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// The controller driving the car, if any.
+    pub fn owner(&self) -> Option<Slot> {
+        self.owner
+    }
+
+    /// The controller waiting for the driver to hand over.
+    pub fn pending_request(&self) -> Option<Slot> {
+        self.request
+    }
+
+    /// The controller whose throttle is limited, in trainer mode.
+    pub fn student(&self) -> Option<Slot> {
+        match self.policy {
+            Policy::Trainer(instructor) => Some(instructor.other()),
+            _ => None,
+        }
+    }
+
+    /// Works out who drives now that the armed state of the controllers is
+    /// `armed(slot)` and `takeover(slot)` tells who is holding takeover.
+    pub fn update(&mut self, armed: impl Fn(Slot) -> bool, takeover: impl Fn(Slot) -> bool) {
+        // The instructor doesn't have to arm, taking over has to be instant
+        if let Policy::Trainer(instructor) = self.policy {
+            let student = instructor.other();
+            self.owner = if takeover(instructor) {
+                Some(instructor)
+            } else if armed(student) {
+                Some(student)
+            } else {
+                None
+            };
+            return;
+        }
+
+        if self.owner.is_some_and(|owner| !armed(owner)) {
+            self.owner = None;
+        }
+        if self.request.is_some_and(|slot| !armed(slot)) {
+            self.request = None;
+        }
+
+        if let Policy::InstructorOverride(instructor) = self.policy {
+            if armed(instructor) {
+                self.owner = Some(instructor);
+            }
+        }
+
+        // Nobody is driving, the first one armed takes it. Left wins a tie.
+        if self.owner.is_none() {
+            self.owner = [Slot::Left, Slot::Right]
+                .into_iter()
+                .find(|&slot| armed(slot));
+        }
+
+        if self.request == self.owner {
+            self.request = None;
+        }
+    }
+
+    /// `slot` pressed its handover buttons. From the driver that accepts a
+    /// pending request, from the other controller it asks for control.
+    /// Returns whether anything changed.
+    pub fn handover(&mut self, slot: Slot, armed: bool) -> bool {
+        if self.policy != Policy::Handover || !armed {
+            return false;
+        }
+
+        if self.owner == Some(slot) {
+            match self.request.take() {
+                Some(requester) => {
+                    self.owner = Some(requester);
+                    true
+                }
+                None => false,
+            }
+        } else if self.owner.is_some() && self.request != Some(slot) {
+            self.request = Some(slot);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(arbiter: &mut Arbiter, left: bool, right: bool) {
+        arbiter.update(
+            |slot| match slot {
+                Slot::Left => left,
+                Slot::Right => right,
+            },
+            |_| false,
+        );
+    }
+
+    #[test]
+    fn first_armed_keeps_control_until_disarming() {
+        let mut arbiter = Arbiter::new(Policy::FirstArmedWins);
+        update(&mut arbiter, false, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+
+        update(&mut arbiter, true, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+
+        update(&mut arbiter, true, false);
+        assert_eq!(arbiter.owner(), Some(Slot::Left));
+
+        update(&mut arbiter, false, false);
+        assert_eq!(arbiter.owner(), None);
+    }
+
+    #[test]
+    fn first_armed_tie_goes_left() {
+        let mut arbiter = Arbiter::new(Policy::FirstArmedWins);
+        update(&mut arbiter, true, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Left));
+    }
+
+    #[test]
+    fn instructor_overrides_when_armed() {
+        let mut arbiter = Arbiter::new(Policy::InstructorOverride(Slot::Left));
+        update(&mut arbiter, false, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+
+        update(&mut arbiter, true, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Left));
+
+        // The instructor disarming hands back to the student
+        update(&mut arbiter, false, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+
+        update(&mut arbiter, false, false);
+        assert_eq!(arbiter.owner(), None);
+    }
+
+    #[test]
+    fn handover_is_asked_for_and_accepted() {
+        let mut arbiter = Arbiter::new(Policy::Handover);
+        update(&mut arbiter, true, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Left));
+
+        // Asking twice changes nothing the second time
+        assert!(arbiter.handover(Slot::Right, true));
+        assert!(!arbiter.handover(Slot::Right, true));
+        assert_eq!(arbiter.pending_request(), Some(Slot::Right));
+        assert_eq!(arbiter.owner(), Some(Slot::Left));
+
+        assert!(arbiter.handover(Slot::Left, true));
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+        assert_eq!(arbiter.pending_request(), None);
+
+        update(&mut arbiter, true, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+    }
+
+    #[test]
+    fn handover_needs_arming_and_the_policy() {
+        let mut arbiter = Arbiter::new(Policy::Handover);
+        update(&mut arbiter, true, false);
+        assert!(!arbiter.handover(Slot::Right, false));
+        assert!(!arbiter.handover(Slot::Left, true));
+
+        let mut arbiter = Arbiter::new(Policy::FirstArmedWins);
+        update(&mut arbiter, true, true);
+        assert!(!arbiter.handover(Slot::Right, true));
+    }
+
+    #[test]
+    fn handover_request_is_dropped_when_the_requester_disarms() {
+        let mut arbiter = Arbiter::new(Policy::Handover);
+        update(&mut arbiter, true, true);
+        arbiter.handover(Slot::Right, true);
+
+        update(&mut arbiter, true, false);
+        assert_eq!(arbiter.pending_request(), None);
+        assert!(!arbiter.handover(Slot::Left, true));
+        assert_eq!(arbiter.owner(), Some(Slot::Left));
+    }
+
+    #[test]
+    fn handover_owner_disarming_passes_control() {
+        let mut arbiter = Arbiter::new(Policy::Handover);
+        update(&mut arbiter, true, true);
+        arbiter.handover(Slot::Right, true);
+
+        update(&mut arbiter, false, true);
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+        assert_eq!(arbiter.pending_request(), None);
+    }
+
+    #[test]
+    fn trainer_takeover_overrides_the_student() {
+        let mut arbiter = Arbiter::new(Policy::Trainer(Slot::Left));
+        assert_eq!(arbiter.student(), Some(Slot::Right));
+        let armed = |slot| slot == Slot::Right;
+
+        arbiter.update(armed, |_| false);
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+
+        // The instructor doesn't have to be armed to take over
+        arbiter.update(armed, |slot| slot == Slot::Left);
+        assert_eq!(arbiter.owner(), Some(Slot::Left));
+
+        arbiter.update(armed, |_| false);
+        assert_eq!(arbiter.owner(), Some(Slot::Right));
+
+        arbiter.update(|_| false, |_| false);
+        assert_eq!(arbiter.owner(), None);
+    }
+}
@@ -0,0 +1,221 @@
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::profiles::Limits;
+
+// The voltage has to stay down this long before it counts, it sags for a
+// moment whenever the car accelerates hard
+const SAG_TIME: Duration = Duration::from_secs(2);
+// A battery only counts as recovered this far above the warning threshold,
+// anything less is the voltage bouncing back once the load comes off
+const RECOVERY_MARGIN: f32 = 0.5;
+// Largest throttle allowed once the battery is critical
+const CRITICAL_THROTTLE: f32 = 0.3;
+// How long the car can keep driving on a critical battery
+const DISARM_AFTER: Duration = Duration::from_secs(20);
+
+/// Pack voltages the battery guard acts on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryThresholds {
+    /// Below this the controllers warn the driver.
+    pub warning: f32,
+    /// Below this the throttle is cut back, and the car disarmed soon after.
+    pub critical: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum BatteryLevel {
+    #[default]
+    Ok,
+    Warning,
+    Critical,
+}
+
+/// Keeps track of how flat the car's battery is from its telemetry, so it
+/// can't be run down far enough to damage it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BatteryGuard {
+    /// Nothing is guarded without thresholds.
+    thresholds: Option<BatteryThresholds>,
+    level: BatteryLevel,
+    // When the voltage first dropped below the current level
+    low_since: Option<Instant>,
+    critical_since: Option<Instant>,
+}
+
+impl BatteryGuard {
+    pub fn new(thresholds: Option<BatteryThresholds>) -> BatteryGuard {
+        BatteryGuard {
+            thresholds,
+            ..BatteryGuard::default()
+        }
+    }
+
+    pub fn level(&self) -> BatteryLevel {
+        self.level
+    }
+
+    /// Takes in a new voltage reading. The level only gets worse until a
+    /// charged battery goes in.
+    pub fn update(&mut self, voltage: f32, now: Instant) {
+        let Some(thresholds) = self.thresholds else {
+            return;
+        };
+
+        let reading = if voltage < thresholds.critical {
+            BatteryLevel::Critical
+        } else if voltage < thresholds.warning {
+            BatteryLevel::Warning
+        } else {
+            BatteryLevel::Ok
+        };
+
+        if reading > self.level {
+            let since = *self.low_since.get_or_insert(now);
+            if now.saturating_duration_since(since) >= SAG_TIME {
+                self.level = reading;
+                self.low_since = None;
+                match reading {
+                    BatteryLevel::Critical => {
+                        error!(
+                            "Battery critical at {:.1}V, limiting throttle and disarming in {}s",
+                            voltage,
+                            DISARM_AFTER.as_secs()
+                        );
+                        self.critical_since = Some(now);
+                    }
+                    _ => warn!("Battery low at {:.1}V", voltage),
+                }
+            }
+        } else {
+            self.low_since = None;
+
+            if self.level != BatteryLevel::Ok && voltage >= thresholds.warning + RECOVERY_MARGIN {
+                info!("Battery back up at {:.1}V", voltage);
+                self.level = BatteryLevel::Ok;
+                self.critical_since = None;
+            }
+        }
+    }
+
+    /// Whether the battery has been critical long enough that the car has to
+    /// stop.
+    pub fn should_disarm(&self, now: Instant) -> bool {
+        self.critical_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= DISARM_AFTER)
+    }
+
+    /// `limits` with the throttle cut back if the battery is critical.
+    pub fn derate(&self, limits: Limits) -> Limits {
+        match self.level {
+            BatteryLevel::Critical => Limits {
+                max_throttle: limits.max_throttle.min(CRITICAL_THROTTLE),
+                ..limits
+            },
+            BatteryLevel::Ok | BatteryLevel::Warning => limits,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiles::Profile;
+
+    const THRESHOLDS: BatteryThresholds = BatteryThresholds {
+        warning: 14.0,
+        critical: 13.2,
+    };
+
+    /// A guard fed `voltage` every 100ms for `time`, starting at `start`.
+    fn feed(guard: &mut BatteryGuard, voltage: f32, start: Instant, time: Duration) -> Instant {
+        let mut now = start;
+        while now < start + time {
+            guard.update(voltage, now);
+            now += Duration::from_millis(100);
+        }
+        guard.update(voltage, now);
+        now
+    }
+
+    #[test]
+    fn short_sags_are_ignored() {
+        let mut guard = BatteryGuard::new(Some(THRESHOLDS));
+        let mut now = Instant::now();
+        for _ in 0..10 {
+            now = feed(&mut guard, 12.5, now, SAG_TIME / 2);
+            now = feed(&mut guard, 15.0, now, Duration::from_millis(100));
+        }
+        assert_eq!(guard.level(), BatteryLevel::Ok);
+    }
+
+    #[test]
+    fn levels_drop_once_the_voltage_stays_down() {
+        let mut guard = BatteryGuard::new(Some(THRESHOLDS));
+        let now = feed(&mut guard, 13.8, Instant::now(), SAG_TIME);
+        assert_eq!(guard.level(), BatteryLevel::Warning);
+
+        let now = feed(&mut guard, 13.0, now, SAG_TIME - Duration::from_millis(200));
+        assert_eq!(guard.level(), BatteryLevel::Warning);
+        feed(&mut guard, 13.0, now, Duration::from_millis(200));
+        assert_eq!(guard.level(), BatteryLevel::Critical);
+    }
+
+    #[test]
+    fn recovery_needs_the_margin() {
+        let mut guard = BatteryGuard::new(Some(THRESHOLDS));
+        let now = feed(&mut guard, 13.0, Instant::now(), SAG_TIME);
+        assert_eq!(guard.level(), BatteryLevel::Critical);
+
+        // Bouncing back once the load comes off isn't enough
+        let now = feed(&mut guard, THRESHOLDS.warning + 0.2, now, SAG_TIME);
+        assert_eq!(guard.level(), BatteryLevel::Critical);
+
+        feed(
+            &mut guard,
+            THRESHOLDS.warning + RECOVERY_MARGIN,
+            now,
+            Duration::ZERO,
+        );
+        assert_eq!(guard.level(), BatteryLevel::Ok);
+        assert!(!guard.should_disarm(now + DISARM_AFTER));
+    }
+
+    #[test]
+    fn disarms_after_being_critical_for_a_while() {
+        let mut guard = BatteryGuard::new(Some(THRESHOLDS));
+        let critical = feed(&mut guard, 13.0, Instant::now(), SAG_TIME);
+        assert!(!guard.should_disarm(critical));
+        assert!(!guard.should_disarm(critical + DISARM_AFTER / 2));
+        assert!(guard.should_disarm(critical + DISARM_AFTER));
+    }
+
+    #[test]
+    fn derates_only_when_critical() {
+        let sport = Profile::Sport.limits();
+        let beginner = Profile::Beginner.limits();
+        let mut guard = BatteryGuard::new(Some(THRESHOLDS));
+
+        let now = feed(&mut guard, 13.8, Instant::now(), SAG_TIME);
+        assert_eq!(guard.derate(sport), sport);
+
+        feed(&mut guard, 13.0, now, SAG_TIME);
+        let derated = guard.derate(sport);
+        assert_eq!(derated.max_throttle, CRITICAL_THROTTLE);
+        assert_eq!(derated.steering_rate, sport.steering_rate);
+        // Never raises a limit that is already lower
+        assert_eq!(
+            guard.derate(beginner).max_throttle,
+            beginner.max_throttle.min(CRITICAL_THROTTLE)
+        );
+    }
+
+    #[test]
+    fn unguarded_ignores_everything() {
+        let mut guard = BatteryGuard::new(None);
+        let now = feed(&mut guard, 1.0, Instant::now(), DISARM_AFTER * 2);
+        assert_eq!(guard.level(), BatteryLevel::Ok);
+        assert!(!guard.should_disarm(now));
+    }
+}
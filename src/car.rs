@@ -1,68 +1,97 @@
 extern crate serialport;
 
+use sbus_codec::{Flags, Variant};
 use serialport::SerialPort;
+use std::io;
 use std::time::Duration;
 
-use crate::sbus_writer::encode_sbus;
+use crate::latency::Timing;
+use crate::link::Link;
+use crate::sbus_writer::SBusWriter;
 
 //  Define commands
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum CarCommand {
-    SendData(u16, u16, bool, bool),
+    /// Steering, throttle, forward and armed, and when the reading behind
+    /// them was taken if it came from a controller.
+    SendData(u16, u16, bool, bool, Option<Timing>),
     // Add other commands as needed
 }
 
+impl CarCommand {
+    pub fn with_timing(self, timing: Option<Timing>) -> CarCommand {
+        match self {
+            CarCommand::SendData(horizontal, vertical, forward, armed, _) => {
+                CarCommand::SendData(horizontal, vertical, forward, armed, timing)
+            }
+        }
+    }
+}
+
 pub struct Car {
     serial_port: Box<dyn SerialPort>,
+    writer: SBusWriter,
 }
 
 impl Car {
-    pub fn new() -> Car {
+    pub fn new(variant: Variant) -> Car {
         Car {
-            serial_port: Self::init_serial(),
+            serial_port: Self::init_serial(variant),
+            writer: SBusWriter::new(variant),
         }
     }
 
-    fn init_serial() -> Box<dyn SerialPort> {
-        // Define serial port parameters for SBUS
+    fn init_serial(variant: Variant) -> Box<dyn SerialPort> {
         let port_name = "/dev/tty.usbserial-ABSCDGUN"; // Adjust according to your OS and connected device
-        let baud_rate = 100_000; // SBUS baud rate
-        let timeout = Duration::from_millis(10);
-        // Open the serial port with SBUS settings
-        serialport::new(port_name, baud_rate)
-            .data_bits(serialport::DataBits::Eight)
-            .parity(serialport::Parity::Even)
-            .stop_bits(serialport::StopBits::Two)
-            .timeout(timeout)
-            .open()
-            .expect("Failed to open serial port")
+        open_sbus_port(port_name, variant).expect("Failed to open serial port")
     }
+}
 
-    pub fn send_data(&mut self, horizontal: u16, vertical: u16, forward: bool, armed: bool) {
-        let mut channels: [u16; 16] = [1024; 16];
-        channels[0] = horizontal;
-        channels[2] = vertical;
-        channels[6] = 240;
-        channels[7] = 240;
+/// Opens a serial port with the SBUS settings for `variant`: its baud rate,
+/// 8E2. SBUS is an inverted signal, which the serial adapter has to take
+/// care of, the port can't be told to invert.
+pub fn open_sbus_port(
+    port_name: &str,
+    variant: Variant,
+) -> serialport::Result<Box<dyn SerialPort>> {
+    let timeout = Duration::from_millis(10);
+    serialport::new(port_name, variant.baud_rate())
+        .data_bits(serialport::DataBits::Eight)
+        .parity(serialport::Parity::Even)
+        .stop_bits(serialport::StopBits::Two)
+        .timeout(timeout)
+        .open()
+}
 
-        if forward {
-            channels[5] = 1807;
-        } else {
-            channels[5] = 240;
-        }
+impl Link for Car {
+    fn send_channels(&mut self, channels: [u16; 16]) -> io::Result<()> {
+        let packet = self.writer.encode(&channels, Flags::default());
 
-        if armed {
-            channels[4] = 1807;
-        } else {
-            channels[4] = 240;
-        }
+        // println!("writing to serial port: {:?}", packet);
 
-        let packet = encode_sbus(channels);
+        self.serial_port.write_all(&packet)
+    }
+}
 
-        // println!("writing to serial port: {:?}", packet);
+/// The 16 SBUS channel values the car expects for a given control state.
+pub fn build_channels(horizontal: u16, vertical: u16, forward: bool, armed: bool) -> [u16; 16] {
+    let mut channels: [u16; 16] = [1024; 16];
+    channels[0] = horizontal;
+    channels[2] = vertical;
+    channels[6] = 240;
+    channels[7] = 240;
 
-        self.serial_port
-            .write(&packet)
-            .expect("Serial write failed");
+    if forward {
+        channels[5] = 1807;
+    } else {
+        channels[5] = 240;
     }
+
+    if armed {
+        channels[4] = 1807;
+    } else {
+        channels[4] = 240;
+    }
+
+    channels
 }
@@ -0,0 +1,391 @@
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::LevelFilter;
+use sbus_codec::Variant;
+
+use crate::arbitration::Policy;
+use crate::battery::BatteryThresholds;
+use crate::profiles::Profile;
+use crate::secure::MIN_KEY_LEN;
+use crate::tilt::TiltConfig;
+
+/// Where the key comes from when it isn't on the command line.
+pub const KEY_VARIABLE: &str = "GLORB_KEY";
+/// Where the API token comes from when it isn't on the command line.
+pub const API_TOKEN_VARIABLE: &str = "GLORB_API_TOKEN";
+
+pub const USAGE: &str = "\
+Usage: glorb-control [command] [options]
+
+Commands:
+  (none)                 drive from JoyCons and gamepads
+  keyboard               drive from the terminal, for bench tests
+  replay <file>          drive from a session recorded with --record
+  monitor <port>         show the SBUS frames on a serial port
+  proxy <in> <out>       pass the SBUS frames from a receiver on <in> through
+                           to <out>, with the controllers overriding some
+                           channels while one of them is driving
+  vehicle <address>      drive the car from a controller side started with
+                           --remote, listening for it on this UDP address,
+                           e.g. 0.0.0.0:9000
+
+Options:
+  --policy <policy>      who drives when both controllers are armed:
+                           first-armed (default), instructor-left,
+                           instructor-right, handover, trainer-left or
+                           trainer-right
+  --student-throttle <n> scales the student's throttle in trainer mode,
+                           0.0 to 1.0 (default 1.0)
+  --profile <profile>    the drive profile to start in: beginner, normal or
+                           sport (default, no limits). Drivers can switch
+                           with the profile chord
+  --tilt                 steer by tilting the JoyCons like a wheel
+  --max-tilt <degrees>   tilt that gives full steering lock (default 45)
+  --tilt-deadzone <deg>  tilt either side of centre that is ignored (default 3)
+  --tilt-invert          flip the tilt steering direction
+  --log-level <level>    error, warn, info (default), debug or trace
+  --log-json             log JSON lines instead of text
+  --record <file>        record controller readings and SBUS frames to a
+                           JSON-lines file
+  --speed <factor>       replay this many times as fast (default 1.0)
+  --mock-link            log SBUS frames instead of opening the serial port
+  --override <channels>  channels the controllers override in proxy mode,
+                           numbered from 1 (default 1,3,5,6)
+  --sbus <variant>       what the receiver or car speaks: standard (default,
+                           100000 baud every 14ms), high-speed (every 7ms),
+                           fast (200000 baud every 7ms) or sbus2
+  --telemetry <port>     read CRSF telemetry from the car on this serial port
+  --battery-warning <v>  warn the drivers when the car's battery drops below
+                           this many volts
+  --battery-critical <v> limit the throttle when it drops below this, and
+                           disarm soon after. Set both to guard the battery
+  --tui                  show a live dashboard instead of the log, when
+                           driving, proxying or replaying
+  --api <address>        serve the state as JSON and over a WebSocket on this
+                           address, e.g. 127.0.0.1:8080, with a page for the
+                           pit crew at /
+  --api-token <token>    lets requests carrying this token arm, disarm and
+                           e-stop the car through the API. Like the key, it
+                           is better kept in a file or $GLORB_API_TOKEN
+  --api-token-file <file>
+                         read the API token from the first line of this file
+  --remote <address>     send the frames to a vehicle over UDP instead of to
+                           the serial port
+  --max-latency <ms>     round trip time, or age of the channels, past which
+                           the vehicle stops the car (default 100)
+  --key <key>            the key shared by the controller and vehicle sides,
+                           at least 16 characters, e.g. from
+                           `openssl rand -hex 32`. Needed with --remote
+                           and vehicle. Anyone on the machine can see it
+                           here, --key-file or $GLORB_KEY keep it out of
+                           the process list
+  --key-file <file>      read the key from the first line of this file
+
+Environment:
+  GLORB_KEY              the key, when neither --key nor --key-file is given
+  GLORB_API_TOKEN        the API token, when neither --api-token nor
+                           --api-token-file is given";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Drive,
+    Keyboard,
+    /// Replays the recording at this path.
+    Replay(String),
+    /// Decodes the SBUS frames on this serial port.
+    Monitor(String),
+    /// Passes SBUS from the `input` port through to `output`, with the
+    /// controllers layered on top.
+    Proxy {
+        input: String,
+        output: String,
+    },
+    /// Drives the car from frames coming in on this UDP address.
+    Vehicle(String),
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+    pub policy: Policy,
+    pub student_throttle: f32,
+    pub profile: Profile,
+    /// Set when tilt steering is turned on.
+    pub tilt: Option<TiltConfig>,
+    pub log_level: LevelFilter,
+    pub log_json: bool,
+    /// Where to record the session, if anywhere.
+    pub record: Option<String>,
+    /// How much faster than recorded to replay.
+    pub speed: f64,
+    pub mock_link: bool,
+    /// Channels the controllers override in proxy mode, 0 based.
+    pub overridden: Vec<usize>,
+    pub sbus: Variant,
+    /// The serial port the car's telemetry comes in on, if it has its own.
+    pub telemetry: Option<String>,
+    /// Set when the battery is guarded.
+    pub battery: Option<BatteryThresholds>,
+    pub tui: bool,
+    /// Where to serve the API, if anywhere.
+    pub api: Option<String>,
+    /// Needed to control the car through the API, which can't without one.
+    pub api_token: Option<String>,
+    /// The vehicle to send frames to, when it isn't this machine.
+    pub remote: Option<String>,
+    /// Seals everything sent between the controller and vehicle sides.
+    pub key: Option<String>,
+    pub max_latency: Duration,
+}
+
+fn value<T: FromStr>(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<T, String> {
+    let value = args
+        .next()
+        .ok_or_else(|| format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+/// The first line of the file at `path`, for secrets kept off the command
+/// line.
+fn first_line(path: &str) -> Result<String, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    Ok(contents.lines().next().unwrap_or_default().to_owned())
+}
+
+/// Parses channel numbers like `1,3,5` into 0 based indices.
+fn channel_list(list: &str) -> Result<Vec<usize>, String> {
+    list.split(',')
+        .map(|channel| match channel.trim().parse::<usize>() {
+            Ok(number @ 1..=16) => Ok(number - 1),
+            _ => Err(format!("Invalid channel: {}", channel)),
+        })
+        .collect()
+}
+
+impl Options {
+    /// Parses the command line, without the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+        let mut args = args.into_iter();
+        let mut command = None;
+        let mut policy = Policy::default();
+        let mut student_throttle = 1.0;
+        let mut profile = Profile::default();
+        let mut tilt: Option<TiltConfig> = None;
+        let mut log_level = LevelFilter::Info;
+        let mut log_json = false;
+        let mut record = None;
+        let mut speed: f64 = 1.0;
+        let mut mock_link = false;
+        // Steering, throttle, arming and direction
+        let mut overridden = vec![0, 2, 4, 5];
+        let mut sbus = Variant::default();
+        let mut telemetry = None;
+        let mut battery_warning: Option<f32> = None;
+        let mut battery_critical: Option<f32> = None;
+        let mut tui = false;
+        let mut api = None;
+        let mut api_token = None;
+        let mut remote = None;
+        let mut key: Option<String> = None;
+        let mut max_latency = Duration::from_millis(100);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--policy" => policy = value(&mut args, &arg)?,
+                "--student-throttle" => {
+                    student_throttle = value(&mut args, &arg)?;
+                    if !(0.0..=1.0).contains(&student_throttle) {
+                        return Err("--student-throttle must be 0.0 to 1.0".to_owned());
+                    }
+                }
+                "--profile" => profile = value(&mut args, &arg)?,
+                "--tilt" => {
+                    tilt.get_or_insert_with(TiltConfig::default);
+                }
+                "--max-tilt" => {
+                    tilt.get_or_insert_with(TiltConfig::default).max_tilt = value(&mut args, &arg)?;
+                }
+                "--tilt-deadzone" => {
+                    tilt.get_or_insert_with(TiltConfig::default).deadzone = value(&mut args, &arg)?;
+                }
+                "--tilt-invert" => {
+                    tilt.get_or_insert_with(TiltConfig::default).invert = true;
+                }
+                "--log-level" => log_level = value(&mut args, &arg)?,
+                "--log-json" => log_json = true,
+                "--record" => record = Some(value(&mut args, &arg)?),
+                "--speed" => {
+                    speed = value(&mut args, &arg)?;
+                    if !speed.is_finite() || speed <= 0.0 {
+                        return Err("--speed must be a number more than 0".to_owned());
+                    }
+                }
+                "--mock-link" => mock_link = true,
+                "--override" => overridden = channel_list(&value::<String>(&mut args, &arg)?)?,
+                "--sbus" => sbus = value(&mut args, &arg)?,
+                "--telemetry" => telemetry = Some(value(&mut args, &arg)?),
+                "--battery-warning" => battery_warning = Some(value(&mut args, &arg)?),
+                "--battery-critical" => battery_critical = Some(value(&mut args, &arg)?),
+                "--tui" => tui = true,
+                "--api" => api = Some(value(&mut args, &arg)?),
+                "--api-token" => api_token = Some(value(&mut args, &arg)?),
+                "--api-token-file" => {
+                    api_token = Some(first_line(&value::<String>(&mut args, &arg)?)?)
+                }
+                "--remote" => remote = Some(value(&mut args, &arg)?),
+                "--key" => key = Some(value(&mut args, &arg)?),
+                "--key-file" => key = Some(first_line(&value::<String>(&mut args, &arg)?)?),
+                "--max-latency" => max_latency = Duration::from_millis(value(&mut args, &arg)?),
+                "keyboard" if command.is_none() => command = Some(Command::Keyboard),
+                "replay" if command.is_none() => {
+                    command = Some(Command::Replay(value(&mut args, &arg)?))
+                }
+                "monitor" if command.is_none() => {
+                    command = Some(Command::Monitor(value(&mut args, &arg)?))
+                }
+                "vehicle" if command.is_none() => {
+                    command = Some(Command::Vehicle(value(&mut args, &arg)?))
+                }
+                "proxy" if command.is_none() => {
+                    command = Some(Command::Proxy {
+                        input: value(&mut args, &arg)?,
+                        output: value(&mut args, &arg)?,
+                    })
+                }
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+
+        let battery = match (battery_warning, battery_critical) {
+            (Some(warning), Some(critical)) if critical < warning => {
+                Some(BatteryThresholds { warning, critical })
+            }
+            (Some(_), Some(_)) => {
+                return Err("--battery-critical must be below --battery-warning".to_owned())
+            }
+            (None, None) => None,
+            _ => return Err("--battery-warning and --battery-critical go together".to_owned()),
+        };
+
+        let command = command.unwrap_or(Command::Drive);
+        // Both of these want the terminal to themselves
+        if tui
+            && matches!(
+                command,
+                Command::Keyboard | Command::Monitor(_) | Command::Vehicle(_)
+            )
+        {
+            return Err("--tui doesn't work with keyboard, monitor or vehicle".to_owned());
+        }
+
+        let networked = remote.is_some() || matches!(command, Command::Vehicle(_));
+        let key = key.or_else(|| env::var(KEY_VARIABLE).ok());
+        match &key {
+            None if networked => {
+                return Err(format!(
+                    "--remote and vehicle need a --key, --key-file or ${}",
+                    KEY_VARIABLE
+                ))
+            }
+            Some(key) if key.len() < MIN_KEY_LEN => {
+                return Err(format!(
+                    "The key must be at least {} characters",
+                    MIN_KEY_LEN
+                ))
+            }
+            _ => {}
+        }
+
+        let api_token = api_token.or_else(|| env::var(API_TOKEN_VARIABLE).ok());
+        if api_token.as_deref() == Some("") {
+            return Err("The API token can't be empty".to_owned());
+        }
+
+        Ok(Options {
+            command,
+            policy,
+            student_throttle,
+            profile,
+            tilt,
+            log_level,
+            log_json,
+            record,
+            speed,
+            mock_link,
+            overridden,
+            sbus,
+            telemetry,
+            battery,
+            tui,
+            api,
+            api_token,
+            remote,
+            key,
+            max_latency,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn key_comes_from_the_first_line_of_a_key_file() {
+        let path = env::temp_dir().join(format!("glorb-key-{}", std::process::id()));
+        fs::write(&path, "0123456789abcdef0123\nignored\n").unwrap();
+
+        let options = parse(&[
+            "vehicle",
+            "0.0.0.0:9000",
+            "--key-file",
+            path.to_str().unwrap(),
+        ]);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            options.unwrap().key.as_deref(),
+            Some("0123456789abcdef0123")
+        );
+    }
+
+    #[test]
+    fn speed_has_to_be_a_positive_number() {
+        for speed in ["NaN", "inf", "-inf", "0", "-2"] {
+            assert!(parse(&["replay", "session.jsonl", "--speed", speed]).is_err());
+        }
+        let options = parse(&["replay", "session.jsonl", "--speed", "2.5"]);
+        assert_eq!(options.unwrap().speed, 2.5);
+    }
+
+    #[test]
+    fn api_token_comes_from_a_file() {
+        let path = env::temp_dir().join(format!("glorb-api-token-{}", std::process::id()));
+        fs::write(&path, "let me in\n").unwrap();
+
+        let options = parse(&["--api-token-file", path.to_str().unwrap()]);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(options.unwrap().api_token.as_deref(), Some("let me in"));
+    }
+
+    #[test]
+    fn empty_api_tokens_are_refused() {
+        assert!(parse(&["--api-token", ""]).is_err());
+        assert!(parse(&["--api-token-file", "/dev/null"]).is_err());
+    }
+
+    #[test]
+    fn short_keys_are_refused() {
+        assert!(parse(&["vehicle", "0.0.0.0:9000", "--key", "short"]).is_err());
+        assert!(parse(&["vehicle", "0.0.0.0:9000", "--key-file", "/nonexistent"]).is_err());
+    }
+}
@@ -0,0 +1,509 @@
+use std::collections::HashSet;
+use std::sync::{mpsc::Sender, Arc};
+use std::time::Instant;
+
+use arc_swap::ArcSwap;
+use log::{debug, info, warn};
+
+use crate::car::CarCommand;
+use crate::drive_mode::ModeEvent;
+use crate::feedback::{player_lights, FeedbackEvent};
+use crate::input::{Button, ControllerInput, InputError, InputSource};
+use crate::latency::Timing;
+use crate::state_manager::{update_shared, Slot, StateManager};
+use crate::utils::{map_axis, mix_joycon_states};
+
+// Consecutive failed reads before the controller's signal counts as low
+const LOW_SIGNAL_FAILED_READS: u32 = 3;
+
+/// Which buttons have to be held to trigger each action. Every button in a
+/// list has to be pressed at the same time.
+#[derive(Debug)]
+pub struct Bindings {
+    /// Identifies the layout in recordings.
+    pub name: &'static str,
+    pub forward: &'static [Button],
+    pub reverse: &'static [Button],
+    pub arm: &'static [Button],
+    pub disarm: &'static [Button],
+    /// Emergency stop, for both controllers.
+    pub estop: &'static [Button],
+    /// Asks for control, or hands it over when pressed by whoever is driving.
+    pub handover: &'static [Button],
+    /// Held by the instructor to drive in trainer mode.
+    pub takeover: &'static [Button],
+    /// Switches to the next drive profile when tapped.
+    pub profile: &'static [Button],
+    /// Restarts tilt steering calibration.
+    pub recalibrate: &'static [Button],
+}
+
+pub const LEFT_JOYCON: Bindings = Bindings {
+    name: "left-joycon",
+    forward: &[Button::Up],
+    reverse: &[Button::Down],
+    arm: &[Button::Left, Button::Right],
+    disarm: &[Button::SL, Button::SR],
+    estop: &[Button::Capture, Button::Minus],
+    handover: &[Button::Minus],
+    takeover: &[Button::ZL],
+    profile: &[Button::L, Button::Minus],
+    recalibrate: &[Button::Capture],
+};
+
+pub const RIGHT_JOYCON: Bindings = Bindings {
+    name: "right-joycon",
+    forward: &[Button::X],
+    reverse: &[Button::B],
+    arm: &[Button::Y, Button::A],
+    disarm: &[Button::SL, Button::SR],
+    estop: &[Button::Home, Button::Plus],
+    handover: &[Button::Plus],
+    takeover: &[Button::ZR],
+    profile: &[Button::R, Button::Plus],
+    recalibrate: &[Button::Home],
+};
+
+// The terminal has no chords, so it sends single virtual buttons
+pub const KEYBOARD: Bindings = Bindings {
+    name: "keyboard",
+    forward: &[Button::X],
+    reverse: &[Button::B],
+    arm: &[Button::Plus],
+    disarm: &[Button::Minus],
+    estop: &[Button::Home],
+    handover: &[],
+    takeover: &[],
+    profile: &[Button::Capture],
+    recalibrate: &[],
+};
+
+// Like the left JoyCon, but a d-pad hat can't hold left and right together so
+// arming uses select + start, and the shoulder buttons stand in for SL/SR. A
+// pad has enough buttons that no binding shares one with another
+pub const GAMEPAD: Bindings = Bindings {
+    name: "gamepad",
+    forward: &[Button::Up],
+    reverse: &[Button::Down],
+    arm: &[Button::Minus, Button::Plus],
+    disarm: &[Button::L, Button::R],
+    estop: &[Button::Home],
+    handover: &[Button::Y],
+    takeover: &[Button::ZR],
+    profile: &[Button::A, Button::B],
+    recalibrate: &[Button::Capture],
+};
+
+/// Looks up a layout by its name, for replaying recordings.
+pub fn bindings_named(name: &str) -> Option<&'static Bindings> {
+    [&LEFT_JOYCON, &RIGHT_JOYCON, &KEYBOARD, &GAMEPAD]
+        .into_iter()
+        .find(|bindings| bindings.name == name)
+}
+
+pub fn held(buttons: &HashSet<Button>, chord: &[Button]) -> bool {
+    !chord.is_empty() && chord.iter().all(|b| buttons.contains(b))
+}
+
+/// Spots a tap of a binding that shares a button with a chord, like handover
+/// on Minus and emergency stop on Capture + Minus. It only counts once let
+/// go, and only if nothing else was pressed while it was held, so starting
+/// the chord with it doesn't set it off.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tap {
+    held: bool,
+    /// Set once another button is pressed during the hold.
+    spoiled: bool,
+}
+
+impl Tap {
+    /// Whether `chord` was tapped, as of this reading.
+    pub fn update(&mut self, buttons: &HashSet<Button>, chord: &[Button]) -> bool {
+        let held = held(buttons, chord);
+        let tapped = self.held && !held && !self.spoiled;
+
+        self.spoiled = held && (self.spoiled || buttons.len() > chord.len());
+        self.held = held;
+        tapped
+    }
+}
+
+/// The mode change the held buttons ask for. Only one counts per reading:
+/// emergency stop first, then direction, then arming, then disarming.
+fn requested_action(buttons: &HashSet<Button>, bindings: &Bindings) -> Option<ModeEvent> {
+    if held(buttons, bindings.estop) {
+        Some(ModeEvent::EStop)
+    } else if held(buttons, bindings.reverse) {
+        Some(ModeEvent::Reverse)
+    } else if held(buttons, bindings.forward) {
+        Some(ModeEvent::Forward)
+    } else if held(buttons, bindings.arm) {
+        Some(ModeEvent::Arm)
+    } else if held(buttons, bindings.disarm) {
+        Some(ModeEvent::Disarm)
+    } else {
+        None
+    }
+}
+
+/// Moves the controller in `slot` through its drive modes from the buttons it
+/// is holding and its throttle. Buttons only count when first pressed.
+pub fn apply_bindings(
+    state: &mut StateManager,
+    slot: Slot,
+    input: &ControllerInput,
+    bindings: &Bindings,
+) {
+    let action = requested_action(&input.buttons, bindings);
+    if action != state.slot(slot).action_held {
+        if let Some(event) = action {
+            debug!("{}: {:?} requested", input.device, event);
+            state.transition(slot, event, input.vertical);
+        }
+    }
+    state.slot_mut(slot).action_held = action;
+    state.slot_mut(slot).throttle = input.vertical;
+
+    state.transition(slot, ModeEvent::Throttle, input.vertical);
+}
+
+/// The command to send for a controller's mapped stick values, if it is in
+/// control of the car. While nobody is, every controller keeps the car
+/// disarmed and centred so it doesn't hold on to the last command.
+pub fn command_for(
+    state: &StateManager,
+    slot: Slot,
+    horizontal_mapped: u16,
+    vertical_mapped: u16,
+) -> Option<CarCommand> {
+    if state.arbiter.owner().is_none() {
+        let centre = map_axis(0.0, false);
+        return Some(CarCommand::SendData(centre, centre, true, false, None));
+    }
+    if !state.in_control(slot) {
+        return None;
+    }
+
+    let (forward, armed) = mix_joycon_states(state);
+    Some(CarCommand::SendData(
+        horizontal_mapped,
+        vertical_mapped,
+        forward,
+        armed,
+        None,
+    ))
+}
+
+/// Applies one reading from the controller in `slot` to `state` and returns
+/// the command it produced, if any.
+pub fn update_state(
+    state: &mut StateManager,
+    slot: Slot,
+    input: &ControllerInput,
+    bindings: &Bindings,
+) -> Option<CarCommand> {
+    apply_bindings(state, slot, input, bindings);
+    state.slot_mut(slot).takeover = held(&input.buttons, bindings.takeover);
+    state.arbitrate();
+
+    let controller = state.slot_mut(slot);
+    let pressed = controller
+        .handover
+        .update(&input.buttons, bindings.handover);
+    let armed = controller.mode.is_armed();
+
+    if pressed && state.arbiter.handover(slot, armed) {
+        match state.arbiter.pending_request() {
+            Some(_) => info!("{}: asking {:?} for control", input.device, slot.other()),
+            None => info!(
+                "{}: handed control over to {:?}",
+                input.device,
+                slot.other()
+            ),
+        }
+    }
+
+    // Only the driver can change profile, and in trainer mode not the student
+    let pressed = state
+        .slot_mut(slot)
+        .profile
+        .update(&input.buttons, bindings.profile);
+
+    if pressed && state.in_control(slot) && state.arbiter.student() != Some(slot) {
+        state.profile = state.profile.next();
+        info!("{}: {:?} profile", input.device, state.profile);
+    }
+
+    let forward = state
+        .driving_mode()
+        .filter(|_| state.in_control(slot))
+        .unwrap_or(state.slot(slot).mode)
+        .is_forward();
+    let horizontal_mapped = map_axis(input.horizontal, forward);
+    let vertical = if state.arbiter.student() == Some(slot) {
+        input.vertical * state.student_throttle
+    } else {
+        input.vertical
+    };
+    let vertical_mapped = map_axis(vertical, false);
+
+    command_for(state, slot, horizontal_mapped, vertical_mapped)
+}
+
+/// Like `update_state`, but on the shared state. The command carries when
+/// `input` was read and mapped, to time it on its way to the car.
+pub fn handle_input(
+    state_store: &ArcSwap<StateManager>,
+    slot: Slot,
+    input: &ControllerInput,
+    bindings: &Bindings,
+) -> Option<CarCommand> {
+    let mut command = None;
+    update_shared(state_store, |state| {
+        command = update_state(state, slot, input, bindings);
+    });
+
+    let timing = Timing {
+        read: input.timestamp,
+        mapped: Instant::now(),
+    };
+    command.map(|command| command.with_timing(Some(timing)))
+}
+
+/// Reads `source` until it closes, driving the controller state in `slot`.
+pub fn run_input_source(
+    mut source: Box<dyn InputSource>,
+    slot: Slot,
+    state_store: Arc<ArcSwap<StateManager>>,
+    car_tx: Sender<CarCommand>,
+) {
+    let bindings = source.bindings();
+    let mut last_seen = state_store.load_full();
+    let mut lights = None;
+    let mut failed_reads = 0;
+
+    loop {
+        match source.read_input() {
+            Ok(input) => {
+                failed_reads = 0;
+
+                if let Some(command) = handle_input(&state_store, slot, &input, bindings) {
+                    car_tx.send(command).unwrap();
+                }
+
+                // Compare against what this controller saw last, so changes made
+                // by other threads in the meantime are picked up too
+                let state = state_store.load_full();
+                for event in state.events_since(&last_seen, slot) {
+                    source.feedback(event);
+                }
+
+                let new_lights = player_lights(&state, slot);
+                if lights != Some(new_lights) {
+                    source.indicate(new_lights);
+                    lights = Some(new_lights);
+                }
+
+                last_seen = state;
+            }
+            Err(InputError::Closed) => return,
+            Err(e) => {
+                warn!("{:?} controller: {}", slot, e);
+
+                failed_reads += 1;
+                if failed_reads == LOW_SIGNAL_FAILED_READS {
+                    source.feedback(FeedbackEvent::LowSignal);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitration::Policy;
+    use crate::drive_mode::DriveMode;
+
+    fn buttons(buttons: &[Button]) -> HashSet<Button> {
+        buttons.iter().copied().collect()
+    }
+
+    fn reading(held: &[Button], horizontal: f32, vertical: f32) -> ControllerInput {
+        ControllerInput {
+            device: "test".into(),
+            timestamp: Instant::now(),
+            horizontal,
+            vertical,
+            buttons: buttons(held),
+            motion: Vec::new(),
+        }
+    }
+
+    /// The direction, steering and arming a command asks for.
+    fn drive(command: Option<CarCommand>) -> (u16, bool, bool) {
+        match command {
+            Some(CarCommand::SendData(horizontal, _, forward, armed, _)) => {
+                (horizontal, forward, armed)
+            }
+            other => panic!("expected a command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn instructor_takes_over_a_reversing_student() {
+        let mut state = StateManager::new(Policy::Trainer(Slot::Left));
+        state.r.mode = DriveMode::Reverse;
+        state.arbitrate();
+        assert!(state.in_control(Slot::Right));
+
+        // Taking over unarmed keeps the car reversing and armed
+        let takeover = [Button::ZL];
+        let command = update_state(
+            &mut state,
+            Slot::Left,
+            &reading(&takeover, 0.5, 0.3),
+            &LEFT_JOYCON,
+        );
+        assert!(state.in_control(Slot::Left));
+        assert_eq!(state.l.mode, DriveMode::Disarmed);
+        assert_eq!(drive(command), (map_axis(0.5, false), false, true));
+
+        // The student changing direction meanwhile still counts, as the
+        // instructor drives in their mode
+        update_state(
+            &mut state,
+            Slot::Right,
+            &reading(&[Button::X], 0.0, 0.0),
+            &RIGHT_JOYCON,
+        );
+        let command = update_state(
+            &mut state,
+            Slot::Left,
+            &reading(&takeover, 0.5, 0.3),
+            &LEFT_JOYCON,
+        );
+        assert_eq!(drive(command), (map_axis(0.5, true), true, true));
+
+        // Once the instructor arms, their own mode counts
+        let arm = [Button::ZL, Button::Left, Button::Right];
+        update_state(
+            &mut state,
+            Slot::Left,
+            &reading(&arm, 0.0, 0.0),
+            &LEFT_JOYCON,
+        );
+        let reverse = [Button::ZL, Button::Down];
+        update_state(
+            &mut state,
+            Slot::Left,
+            &reading(&reverse, 0.0, 0.0),
+            &LEFT_JOYCON,
+        );
+        assert_eq!(state.l.mode, DriveMode::Reverse);
+        assert_eq!(state.r.mode, DriveMode::ArmedNeutral);
+        let command = update_state(
+            &mut state,
+            Slot::Left,
+            &reading(&takeover, 0.5, 0.3),
+            &LEFT_JOYCON,
+        );
+        assert_eq!(drive(command), (map_axis(0.5, false), false, true));
+    }
+
+    #[test]
+    fn profile_chord_switches_once_let_go() {
+        let mut state = StateManager::new(Policy::Handover);
+        state.l.mode = DriveMode::ArmedNeutral;
+        state.r.mode = DriveMode::ArmedNeutral;
+        state.arbitrate();
+        state.arbiter.handover(Slot::Right, true);
+        let profile = state.profile;
+
+        // The shoulder button alone, as while driving, doesn't switch
+        for held in [&[Button::L][..], &[]] {
+            update_state(
+                &mut state,
+                Slot::Left,
+                &reading(held, 0.0, 0.0),
+                &LEFT_JOYCON,
+            );
+        }
+        assert_eq!(state.profile, profile);
+
+        for held in [&[Button::L][..], &[Button::L, Button::Minus], &[]] {
+            update_state(
+                &mut state,
+                Slot::Left,
+                &reading(held, 0.0, 0.0),
+                &LEFT_JOYCON,
+            );
+        }
+        assert_eq!(state.profile, profile.next());
+        // Minus was part of the chord, not a tap accepting the handover
+        assert_eq!(state.arbiter.pending_request(), Some(Slot::Right));
+        assert!(state.in_control(Slot::Left));
+    }
+
+    #[test]
+    fn no_gamepad_binding_shares_a_button() {
+        let chords = [
+            GAMEPAD.forward,
+            GAMEPAD.reverse,
+            GAMEPAD.arm,
+            GAMEPAD.disarm,
+            GAMEPAD.estop,
+            GAMEPAD.handover,
+            GAMEPAD.takeover,
+            GAMEPAD.profile,
+            GAMEPAD.recalibrate,
+        ];
+        let all: Vec<Button> = chords
+            .iter()
+            .flat_map(|chord| chord.iter().copied())
+            .collect();
+        let unique: HashSet<Button> = all.iter().copied().collect();
+        assert_eq!(all.len(), unique.len());
+    }
+
+    #[test]
+    fn tap_counts_on_release() {
+        let mut tap = Tap::default();
+        assert!(!tap.update(&buttons(&[Button::Minus]), LEFT_JOYCON.handover));
+        assert!(tap.update(&buttons(&[]), LEFT_JOYCON.handover));
+        assert!(!tap.update(&buttons(&[]), LEFT_JOYCON.handover));
+    }
+
+    #[test]
+    fn starting_a_chord_is_not_a_tap() {
+        let mut tap = Tap::default();
+        assert!(!tap.update(&buttons(&[Button::Minus]), LEFT_JOYCON.handover));
+        assert!(!tap.update(
+            &buttons(&[Button::Minus, Button::Capture]),
+            LEFT_JOYCON.handover
+        ));
+        // Letting go of Capture first still doesn't make it a tap
+        assert!(!tap.update(&buttons(&[Button::Minus]), LEFT_JOYCON.handover));
+        assert!(!tap.update(&buttons(&[]), LEFT_JOYCON.handover));
+    }
+
+    #[test]
+    fn partial_estop_chord_does_not_hand_over() {
+        let mut left = Tap::default();
+        let mut recalibrate = Tap::default();
+        for held in [
+            buttons(&[Button::Capture]),
+            buttons(&[Button::Capture, Button::Minus]),
+            buttons(&[Button::Minus]),
+            buttons(&[]),
+        ] {
+            assert!(!left.update(&held, LEFT_JOYCON.handover));
+            assert!(!recalibrate.update(&held, LEFT_JOYCON.recalibrate));
+            assert_ne!(requested_action(&held, &LEFT_JOYCON), Some(ModeEvent::Arm));
+        }
+        assert_eq!(
+            requested_action(&buttons(&[Button::Capture, Button::Minus]), &LEFT_JOYCON),
+            Some(ModeEvent::EStop)
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+
+// Throttle either side of centre that counts as stopped
+const NEUTRAL_BAND: f32 = 0.1;
+
+/// Where a controller is in arming and driving the car.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DriveMode {
+    #[default]
+    Disarmed,
+    /// Armed with the throttle centred, forward selected.
+    ArmedNeutral,
+    /// Armed and driving forward.
+    Driving,
+    /// Armed with reverse selected, whatever the throttle.
+    Reverse,
+    /// The link to the car went down. Everything waits for it to come back,
+    /// then starts again disarmed.
+    Failsafe,
+    /// Emergency stop. Only disarming gets out of it.
+    EStop,
+}
+
+/// Something that can move a controller to another mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeEvent {
+    Arm,
+    Disarm,
+    Forward,
+    Reverse,
+    /// A new throttle reading.
+    Throttle,
+    LinkLost,
+    LinkRestored,
+    EStop,
+}
+
+/// A transition that isn't allowed, and why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rejected {
+    pub mode: DriveMode,
+    pub event: ModeEvent,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} rejected in {:?}: {}",
+            self.event, self.mode, self.reason
+        )
+    }
+}
+
+fn neutral(throttle: f32) -> bool {
+    throttle.abs() <= NEUTRAL_BAND
+}
+
+impl DriveMode {
+    /// Whether the car should be armed in this mode.
+    pub fn is_armed(self) -> bool {
+        matches!(
+            self,
+            DriveMode::ArmedNeutral | DriveMode::Driving | DriveMode::Reverse
+        )
+    }
+
+    /// Whether the car should be going forward in this mode.
+    pub fn is_forward(self) -> bool {
+        self != DriveMode::Reverse
+    }
+
+    /// The mode after `event`, with the throttle at `throttle` (-1.0..1.0).
+    pub fn next(self, event: ModeEvent, throttle: f32) -> Result<DriveMode, Rejected> {
+        use DriveMode::*;
+
+        let reject = |reason| {
+            Err(Rejected {
+                mode: self,
+                event,
+                reason,
+            })
+        };
+
+        match (self, event) {
+            (_, ModeEvent::EStop) => Ok(EStop),
+            (EStop, ModeEvent::Disarm) => Ok(Disarmed),
+            (EStop, ModeEvent::Throttle | ModeEvent::LinkLost | ModeEvent::LinkRestored) => {
+                Ok(EStop)
+            }
+            (EStop, _) => reject("emergency stopped, disarm to reset"),
+
+            (_, ModeEvent::LinkLost) => Ok(Failsafe),
+            (Failsafe, ModeEvent::LinkRestored) => Ok(Disarmed),
+            (Failsafe, ModeEvent::Throttle) => Ok(Failsafe),
+            (Failsafe, _) => reject("the link to the car is down"),
+            (_, ModeEvent::LinkRestored) => Ok(self),
+
+            (Disarmed, ModeEvent::Arm) if neutral(throttle) => Ok(ArmedNeutral),
+            (Disarmed, ModeEvent::Arm) => reject("centre the throttle to arm"),
+            (Disarmed, ModeEvent::Reverse) => reject("arm first"),
+            (Disarmed, _) => Ok(Disarmed),
+
+            (_, ModeEvent::Disarm) => Ok(Disarmed),
+            (_, ModeEvent::Arm) => Ok(self),
+
+            (ArmedNeutral, ModeEvent::Throttle) if !neutral(throttle) => Ok(Driving),
+            (Driving, ModeEvent::Throttle) if neutral(throttle) => Ok(ArmedNeutral),
+            (_, ModeEvent::Throttle) => Ok(self),
+
+            (ArmedNeutral | Driving, ModeEvent::Reverse) if neutral(throttle) => Ok(Reverse),
+            (ArmedNeutral | Driving, ModeEvent::Reverse) => reject("slow down before reversing"),
+            (Reverse, ModeEvent::Forward) if neutral(throttle) => Ok(ArmedNeutral),
+            (Reverse, ModeEvent::Forward) => reject("slow down before going forward"),
+            (_, ModeEvent::Forward | ModeEvent::Reverse) => Ok(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DriveMode::*;
+
+    #[test]
+    fn arming_needs_the_throttle_centred() {
+        assert_eq!(Disarmed.next(ModeEvent::Arm, 0.05), Ok(ArmedNeutral));
+        let rejected = Disarmed.next(ModeEvent::Arm, 0.5).unwrap_err();
+        assert_eq!(rejected.mode, Disarmed);
+        assert_eq!(rejected.event, ModeEvent::Arm);
+        assert!(Disarmed.next(ModeEvent::Arm, -0.5).is_err());
+    }
+
+    #[test]
+    fn throttle_moves_between_neutral_and_driving() {
+        assert_eq!(ArmedNeutral.next(ModeEvent::Throttle, 0.5), Ok(Driving));
+        assert_eq!(Driving.next(ModeEvent::Throttle, 0.0), Ok(ArmedNeutral));
+        assert_eq!(Reverse.next(ModeEvent::Throttle, 0.5), Ok(Reverse));
+    }
+
+    #[test]
+    fn estop_is_sticky_until_disarmed() {
+        for mode in [Disarmed, ArmedNeutral, Driving, Reverse, Failsafe, EStop] {
+            assert_eq!(mode.next(ModeEvent::EStop, 0.5), Ok(EStop));
+        }
+
+        for event in [
+            ModeEvent::Throttle,
+            ModeEvent::LinkLost,
+            ModeEvent::LinkRestored,
+        ] {
+            assert_eq!(EStop.next(event, 0.0), Ok(EStop));
+        }
+        for event in [ModeEvent::Arm, ModeEvent::Forward, ModeEvent::Reverse] {
+            assert!(EStop.next(event, 0.0).is_err());
+        }
+        assert_eq!(EStop.next(ModeEvent::Disarm, 0.0), Ok(Disarmed));
+    }
+
+    #[test]
+    fn failsafe_waits_for_the_link_then_disarms() {
+        for mode in [Disarmed, ArmedNeutral, Driving, Reverse] {
+            assert_eq!(mode.next(ModeEvent::LinkLost, 0.0), Ok(Failsafe));
+        }
+
+        assert_eq!(Failsafe.next(ModeEvent::Throttle, 0.5), Ok(Failsafe));
+        for event in [ModeEvent::Arm, ModeEvent::Disarm, ModeEvent::Forward] {
+            assert!(Failsafe.next(event, 0.0).is_err());
+        }
+        assert_eq!(Failsafe.next(ModeEvent::LinkRestored, 0.5), Ok(Disarmed));
+    }
+
+    #[test]
+    fn direction_changes_only_when_stopped() {
+        assert_eq!(ArmedNeutral.next(ModeEvent::Reverse, 0.0), Ok(Reverse));
+        assert!(Driving.next(ModeEvent::Reverse, 0.5).is_err());
+        assert_eq!(Driving.next(ModeEvent::Reverse, 0.0), Ok(Reverse));
+
+        assert_eq!(Reverse.next(ModeEvent::Forward, 0.0), Ok(ArmedNeutral));
+        assert!(Reverse.next(ModeEvent::Forward, -0.5).is_err());
+
+        assert!(Disarmed.next(ModeEvent::Reverse, 0.0).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::battery::BatteryLevel;
+use crate::drive_mode::DriveMode;
+use crate::profiles::Profile;
+use crate::state_manager::{Slot, StateManager};
+
+/// Things worth telling the driver about without them having to look away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedbackEvent {
+    Armed,
+    Disarmed,
+    DirectionChanged,
+    /// The other controller is asking to take over.
+    HandoverRequested,
+    /// Someone hit the emergency stop.
+    EStop,
+    /// The link to the car failed, it is no longer getting our commands.
+    Failsafe,
+    /// The controller itself is struggling to get reports through.
+    LowSignal,
+    /// The car's battery dropped to the warning or critical level.
+    LowBattery,
+}
+
+/// Which of the four player lights are on, in order from the SL button.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PlayerLights {
+    pub steady: [bool; 4],
+    pub flashing: [bool; 4],
+}
+
+/// The player lights for the controller in `slot`. The first light shows
+/// whether it is driving: steady when armed and in control, flashing when
+/// armed but overridden by the other controller, off when disarmed. The
+/// middle two show the drive profile: beginner the second, normal both, sport
+/// the third, flashing once the car's battery is low. The second light
+/// flashes instead while a handover is waiting on the driver. The last light
+/// flashes while reverse is selected. All four flash after an emergency stop.
+pub fn player_lights(state: &StateManager, slot: Slot) -> PlayerLights {
+    let mut lights = PlayerLights::default();
+    let mode = state.slot(slot).mode;
+
+    if mode == DriveMode::EStop {
+        lights.flashing = [true; 4];
+        return lights;
+    }
+
+    if state.in_control(slot) {
+        lights.steady[0] = true;
+    } else if mode.is_armed() {
+        lights.flashing[0] = true;
+    }
+
+    match state.profile {
+        Profile::Beginner => lights.steady[1] = true,
+        Profile::Normal => lights.steady[1..3].fill(true),
+        Profile::Sport => lights.steady[2] = true,
+    }
+    if state.battery.level() > BatteryLevel::Ok {
+        lights.flashing[1..3].copy_from_slice(&lights.steady[1..3]);
+        lights.steady[1..3].fill(false);
+    }
+
+    if state.arbiter.pending_request().is_some() && mode.is_armed() {
+        lights.flashing[1] = true;
+    }
+
+    if !mode.is_forward() {
+        lights.flashing[3] = true;
+    }
+
+    lights
+}
+
+/// One step of a rumble pattern. An amplitude of 0 is a pause.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pulse {
+    pub frequency: f32,
+    pub amplitude: f32,
+    pub duration: Duration,
+}
+
+const fn pulse(frequency: f32, amplitude: f32, millis: u64) -> Pulse {
+    Pulse {
+        frequency,
+        amplitude,
+        duration: Duration::from_millis(millis),
+    }
+}
+
+const fn pause(millis: u64) -> Pulse {
+    pulse(0.0, 0.0, millis)
+}
+
+// Amplitudes stay under 1.0, anything above that can damage the actuators.
+// Only the failsafe comes close, it has to get through whatever else is going on
+
+// Two quick taps
+const ARMED: &[Pulse] = &[pulse(160.0, 0.6, 100), pause(80), pulse(160.0, 0.6, 100)];
+// One long, low thud
+const DISARMED: &[Pulse] = &[pulse(120.0, 0.5, 300)];
+// A short, high tick
+const DIRECTION_CHANGED: &[Pulse] = &[pulse(320.0, 0.4, 80)];
+// A rising pair, a question
+const HANDOVER_REQUESTED: &[Pulse] = &[pulse(200.0, 0.5, 120), pause(60), pulse(280.0, 0.5, 120)];
+// Three hard buzzes, hard to miss
+const FAILSAFE: &[Pulse] = &[
+    pulse(160.0, 0.9, 250),
+    pause(150),
+    pulse(160.0, 0.9, 250),
+    pause(150),
+    pulse(160.0, 0.9, 250),
+];
+// A faint rumble
+const LOW_SIGNAL: &[Pulse] = &[pulse(80.0, 0.3, 500)];
+// A falling pair, running down
+const LOW_BATTERY: &[Pulse] = &[pulse(240.0, 0.6, 150), pause(60), pulse(120.0, 0.6, 250)];
+
+fn pattern(event: FeedbackEvent) -> &'static [Pulse] {
+    match event {
+        FeedbackEvent::Armed => ARMED,
+        FeedbackEvent::Disarmed => DISARMED,
+        FeedbackEvent::DirectionChanged => DIRECTION_CHANGED,
+        FeedbackEvent::HandoverRequested => HANDOVER_REQUESTED,
+        FeedbackEvent::Failsafe | FeedbackEvent::EStop => FAILSAFE,
+        FeedbackEvent::LowSignal => LOW_SIGNAL,
+        FeedbackEvent::LowBattery => LOW_BATTERY,
+    }
+}
+
+/// Plays rumble patterns without blocking. `tick` has to be called regularly,
+/// the JoyCons call it for every report, which is every 16ms.
+#[derive(Default)]
+pub struct RumblePlayer {
+    queue: VecDeque<Pulse>,
+    current_until: Option<Instant>,
+}
+
+impl RumblePlayer {
+    /// Plays the pattern for `event`, cutting short one that has already
+    /// started, what just happened is what the driver needs to hear. Events
+    /// played before the next `tick` follow each other.
+    pub fn play(&mut self, event: FeedbackEvent) {
+        if self.current_until.take().is_some() {
+            self.queue.clear();
+        }
+        self.queue.extend(pattern(event).iter().copied());
+    }
+
+    /// Returns the pulse to start now, if the pattern moved on. Once the queue
+    /// runs out a final zero-amplitude pulse stops the rumble.
+    pub fn tick(&mut self, now: Instant) -> Option<Pulse> {
+        if let Some(until) = self.current_until {
+            if now < until {
+                return None;
+            }
+        }
+
+        match self.queue.pop_front() {
+            Some(next) => {
+                self.current_until = Some(now + next.duration);
+                Some(next)
+            }
+            None => self.current_until.take().map(|_| pause(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitration::Policy;
+    use crate::battery::{BatteryGuard, BatteryThresholds};
+    use crate::state_manager::set_link_ok;
+    use arc_swap::ArcSwap;
+
+    /// Lights from a picture of them, `#` for on: steady, then flashing.
+    fn lights(steady: &str, flashing: &str) -> PlayerLights {
+        let on = |picture: &str| {
+            let mut lights = [false; 4];
+            for (light, c) in lights.iter_mut().zip(picture.chars()) {
+                *light = c == '#';
+            }
+            lights
+        };
+        PlayerLights {
+            steady: on(steady),
+            flashing: on(flashing),
+        }
+    }
+
+    /// A state with the controllers armed in the order given, so the first
+    /// armed one drives.
+    fn state(modes: &[(Slot, DriveMode)]) -> StateManager {
+        let mut state = StateManager::new(Policy::FirstArmedWins);
+        for &(slot, mode) in modes {
+            state.slot_mut(slot).mode = mode;
+            state.arbitrate();
+        }
+        state
+    }
+
+    #[test]
+    fn lights_show_mode_control_and_profile() {
+        use DriveMode::*;
+        use Slot::{Left, Right};
+
+        let low_battery = {
+            let mut state = state(&[(Left, Driving)]);
+            let start = Instant::now();
+            state.battery = BatteryGuard::new(Some(BatteryThresholds {
+                warning: 14.0,
+                critical: 13.0,
+            }));
+            state.battery.update(13.5, start);
+            state.battery.update(13.5, start + Duration::from_secs(5));
+            state
+        };
+        let normal = {
+            let mut state = state(&[]);
+            state.profile = Profile::Normal;
+            state
+        };
+        let handover = {
+            let mut state = StateManager::new(Policy::Handover);
+            state.l.mode = Driving;
+            state.arbitrate();
+            state.r.mode = ArmedNeutral;
+            state.arbitrate();
+            state.arbiter.handover(Right, true);
+            state
+        };
+
+        let cases = [
+            ("disarmed", state(&[]), Left, lights("..#.", "....")),
+            ("normal profile", normal, Left, lights(".##.", "....")),
+            (
+                "driving",
+                state(&[(Left, Driving)]),
+                Left,
+                lights("#.#.", "...."),
+            ),
+            (
+                "overridden",
+                state(&[(Left, Driving), (Right, ArmedNeutral)]),
+                Right,
+                lights("..#.", "#..."),
+            ),
+            (
+                "reversing",
+                state(&[(Right, Reverse)]),
+                Right,
+                lights("#.#.", "...#"),
+            ),
+            (
+                "e-stopped",
+                state(&[(Left, EStop)]),
+                Left,
+                lights("....", "####"),
+            ),
+            ("low battery", low_battery, Left, lights("#...", "..#.")),
+            ("handover waiting", handover, Left, lights("#.#.", ".#..")),
+        ];
+        for (name, state, slot, expected) in cases {
+            assert_eq!(player_lights(&state, slot), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn lights_go_out_when_the_link_is_lost() {
+        let store = ArcSwap::from_pointee(state(&[(Slot::Left, DriveMode::Driving)]));
+        assert_eq!(
+            player_lights(&store.load(), Slot::Left),
+            lights("#.#.", "....")
+        );
+
+        set_link_ok(&store, false);
+        for slot in [Slot::Left, Slot::Right] {
+            assert_eq!(player_lights(&store.load(), slot), lights("..#.", "...."));
+        }
+    }
+
+    /// Everything `player` starts, and when, ticking every 10ms from `start`
+    /// until it has been quiet for a second.
+    fn played(player: &mut RumblePlayer, start: Instant) -> Vec<(Duration, Pulse)> {
+        let mut pulses = Vec::new();
+        let mut now = start;
+        let mut quiet_since = start;
+        while now - quiet_since < Duration::from_secs(1) {
+            if let Some(pulse) = player.tick(now) {
+                pulses.push((now - start, pulse));
+                quiet_since = now;
+            }
+            now += Duration::from_millis(10);
+        }
+        pulses
+    }
+
+    #[test]
+    fn pattern_plays_in_order_then_stops() {
+        let mut player = RumblePlayer::default();
+        player.play(FeedbackEvent::Armed);
+
+        let millis = Duration::from_millis;
+        assert_eq!(
+            played(&mut player, Instant::now()),
+            [
+                (millis(0), ARMED[0]),
+                (millis(100), ARMED[1]),
+                (millis(180), ARMED[2]),
+                (millis(280), pause(0)),
+            ]
+        );
+        assert_eq!(player.tick(Instant::now()), None);
+    }
+
+    #[test]
+    fn new_event_cuts_a_running_pattern_short() {
+        let start = Instant::now();
+        let mut player = RumblePlayer::default();
+        player.play(FeedbackEvent::LowSignal);
+        assert_eq!(player.tick(start), Some(LOW_SIGNAL[0]));
+
+        player.play(FeedbackEvent::EStop);
+        let pulses: Vec<Pulse> = played(&mut player, start + Duration::from_millis(20))
+            .into_iter()
+            .map(|(_, pulse)| pulse)
+            .collect();
+        assert_eq!(pulses[..FAILSAFE.len()], *FAILSAFE);
+        assert_eq!(pulses[FAILSAFE.len()..], [pause(0)]);
+    }
+
+    #[test]
+    fn events_together_follow_each_other() {
+        let mut player = RumblePlayer::default();
+        player.play(FeedbackEvent::Disarmed);
+        player.play(FeedbackEvent::DirectionChanged);
+
+        let pulses: Vec<Pulse> = played(&mut player, Instant::now())
+            .into_iter()
+            .map(|(_, pulse)| pulse)
+            .collect();
+        assert_eq!(pulses, [DISARMED[0], DIRECTION_CHANGED[0], pause(0)]);
+    }
+
+    #[test]
+    fn amplitudes_stay_under_full() {
+        for event in [
+            FeedbackEvent::Armed,
+            FeedbackEvent::Disarmed,
+            FeedbackEvent::DirectionChanged,
+            FeedbackEvent::HandoverRequested,
+            FeedbackEvent::EStop,
+            FeedbackEvent::Failsafe,
+            FeedbackEvent::LowSignal,
+            FeedbackEvent::LowBattery,
+        ] {
+            assert!(pattern(event).iter().all(|pulse| pulse.amplitude < 1.0));
+        }
+    }
+}
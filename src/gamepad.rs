@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use evdev::{AbsoluteAxisType, AttributeSet, AttributeSetRef, Device, Key};
+
+use crate::controls::{Bindings, GAMEPAD};
+use crate::input::{
+    wait_readable, Button, ControllerInput, InputError, InputSource, REPEAT_INTERVAL,
+};
+
+// Buttons are mapped by position rather than by label, so an Xbox "A" (south)
+// behaves like the JoyCon's south button, B.
+const KEY_MAP: [(Key, Button); 16] = [
+    (Key::BTN_DPAD_UP, Button::Up),
+    (Key::BTN_DPAD_DOWN, Button::Down),
+    (Key::BTN_DPAD_LEFT, Button::Left),
+    (Key::BTN_DPAD_RIGHT, Button::Right),
+    (Key::BTN_SOUTH, Button::B),
+    (Key::BTN_EAST, Button::A),
+    (Key::BTN_NORTH, Button::X),
+    (Key::BTN_WEST, Button::Y),
+    (Key::BTN_TL, Button::L),
+    (Key::BTN_TR, Button::R),
+    (Key::BTN_TL2, Button::ZL),
+    (Key::BTN_TR2, Button::ZR),
+    (Key::BTN_START, Button::Plus),
+    (Key::BTN_SELECT, Button::Minus),
+    (Key::BTN_MODE, Button::Home),
+    (Key::BTN_THUMBL, Button::Capture),
+];
+
+/// Any Linux gamepad exposed through evdev (Xbox, DualShock, Steam Controller...).
+pub struct EvdevGamepad {
+    device: Device,
+    path: PathBuf,
+}
+
+impl EvdevGamepad {
+    pub fn name(&self) -> String {
+        format!(
+            "{} ({})",
+            self.device.name().unwrap_or("unnamed gamepad"),
+            self.path.display()
+        )
+    }
+
+    fn axis(&self, axis: AbsoluteAxisType) -> f32 {
+        match self.device.cached_state().abs_vals() {
+            Some(vals) => {
+                let info = vals[axis.0 as usize];
+                normalise_axis(info.value, info.minimum, info.maximum, info.flat)
+            }
+            None => 0.0,
+        }
+    }
+}
+
+/// Where `value` is between `minimum` and `maximum`, as -1.0..1.0. Anything
+/// within `flat` of the centre is 0.0.
+fn normalise_axis(value: i32, minimum: i32, maximum: i32, flat: i32) -> f32 {
+    if maximum <= minimum {
+        return 0.0;
+    }
+
+    let center = (minimum + maximum) as f32 / 2.0;
+    if (value as f32 - center).abs() <= flat as f32 {
+        return 0.0;
+    }
+
+    let half_range = (maximum - minimum) as f32 / 2.0;
+    ((value as f32 - center) / half_range).clamp(-1.0, 1.0)
+}
+
+/// The buttons held, from the keys that are down and the d-pad hat.
+fn buttons_held(keys: &AttributeSetRef<Key>, hat_x: f32, hat_y: f32) -> HashSet<Button> {
+    let mut buttons: HashSet<Button> = KEY_MAP
+        .iter()
+        .filter(|(key, _)| keys.contains(*key))
+        .map(|(_, button)| *button)
+        .collect();
+
+    // Most pads report the d-pad as a hat rather than as buttons
+    if hat_x < 0.0 {
+        buttons.insert(Button::Left);
+    } else if hat_x > 0.0 {
+        buttons.insert(Button::Right);
+    }
+    if hat_y < 0.0 {
+        buttons.insert(Button::Up);
+    } else if hat_y > 0.0 {
+        buttons.insert(Button::Down);
+    }
+    buttons
+}
+
+/// Finds every evdev device that looks like a gamepad: it needs an analog stick
+/// and the south face button.
+pub fn discover_gamepads() -> Vec<EvdevGamepad> {
+    evdev::enumerate()
+        .filter(|(_, device)| {
+            let has_stick = device.supported_absolute_axes().is_some_and(|axes| {
+                axes.contains(AbsoluteAxisType::ABS_X) && axes.contains(AbsoluteAxisType::ABS_Y)
+            });
+            let has_buttons = device
+                .supported_keys()
+                .is_some_and(|keys| keys.contains(Key::BTN_SOUTH));
+
+            has_stick && has_buttons
+        })
+        .map(|(path, device)| EvdevGamepad { device, path })
+        .collect()
+}
+
+impl InputSource for EvdevGamepad {
+    fn read_input(&mut self) -> Result<ControllerInput, InputError> {
+        // Pads only send events when something changes, so with nothing new
+        // the cached state is read out again
+        let ready = wait_readable(self.device.as_raw_fd(), REPEAT_INTERVAL)
+            .map_err(|e| InputError::Device(format!("{}: {}", self.name(), e)))?;
+
+        // Blocks until the next SYN_REPORT, the cached state is updated as events are consumed
+        if ready {
+            if let Err(e) = self
+                .device
+                .fetch_events()
+                .map(|events| events.for_each(drop))
+            {
+                return Err(InputError::Device(format!("{}: {}", self.name(), e)));
+            }
+        }
+
+        let hat_x = self.axis(AbsoluteAxisType::ABS_HAT0X);
+        let hat_y = self.axis(AbsoluteAxisType::ABS_HAT0Y);
+        let buttons = match self.device.cached_state().key_vals() {
+            Some(keys) => buttons_held(keys, hat_x, hat_y),
+            None => buttons_held(&AttributeSet::new(), hat_x, hat_y),
+        };
+
+        Ok(ControllerInput {
+            device: format!("evdev:{}", self.path.display()),
+            timestamp: Instant::now(),
+            horizontal: self.axis(AbsoluteAxisType::ABS_X),
+            // evdev reports "up" as negative
+            vertical: -self.axis(AbsoluteAxisType::ABS_Y),
+            buttons,
+            motion: Vec::new(),
+        })
+    }
+
+    fn bindings(&self) -> &'static Bindings {
+        &GAMEPAD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_covers_the_range() {
+        assert_eq!(normalise_axis(-32768, -32768, 32767, 0), -1.0);
+        assert_eq!(normalise_axis(32767, -32768, 32767, 0), 1.0);
+        assert_eq!(normalise_axis(0, -100, 100, 0), 0.0);
+        assert_eq!(normalise_axis(50, -100, 100, 0), 0.5);
+        assert_eq!(normalise_axis(0, 0, 255, 0), -1.0);
+    }
+
+    #[test]
+    fn axis_is_zero_inside_the_flat_zone() {
+        assert_eq!(normalise_axis(3000, -32768, 32767, 4000), 0.0);
+        assert_eq!(normalise_axis(-3000, -32768, 32767, 4000), 0.0);
+        assert!(normalise_axis(5000, -32768, 32767, 4000) > 0.0);
+    }
+
+    #[test]
+    fn axis_without_a_range_is_zero() {
+        assert_eq!(normalise_axis(10, 0, 0, 0), 0.0);
+        assert_eq!(normalise_axis(10, 5, 1, 0), 0.0);
+    }
+
+    #[test]
+    fn axis_is_clamped() {
+        assert_eq!(normalise_axis(400, 0, 255, 0), 1.0);
+        assert_eq!(normalise_axis(-400, 0, 255, 0), -1.0);
+    }
+
+    #[test]
+    fn keys_map_by_position() {
+        let mut keys = AttributeSet::new();
+        keys.insert(Key::BTN_SOUTH);
+        keys.insert(Key::BTN_SELECT);
+        keys.insert(Key::BTN_THUMBL);
+
+        let buttons = buttons_held(&keys, 0.0, 0.0);
+        assert_eq!(
+            buttons,
+            HashSet::from([Button::B, Button::Minus, Button::Capture])
+        );
+    }
+
+    #[test]
+    fn hat_becomes_the_dpad() {
+        let keys = AttributeSet::new();
+        assert_eq!(
+            buttons_held(&keys, -1.0, -1.0),
+            HashSet::from([Button::Left, Button::Up])
+        );
+        assert_eq!(
+            buttons_held(&keys, 1.0, 1.0),
+            HashSet::from([Button::Right, Button::Down])
+        );
+        assert!(buttons_held(&keys, 0.0, 0.0).is_empty());
+    }
+}
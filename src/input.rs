@@ -0,0 +1,134 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::os::fd::RawFd;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::controls::Bindings;
+use crate::feedback::{FeedbackEvent, PlayerLights};
+
+/// Buttons understood by the control logic. The names follow the JoyCon layout,
+/// other backends map their buttons onto these by position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    X,
+    Y,
+    L,
+    R,
+    ZL,
+    ZR,
+    SL,
+    SR,
+    Plus,
+    Minus,
+    Home,
+    Capture,
+}
+
+/// A single reading from a controller. Stick axes are normalised to -1.0..1.0,
+/// with right and up being positive.
+#[derive(Debug, Clone)]
+pub struct ControllerInput {
+    /// Identifies the physical controller, e.g. its serial number or device node.
+    pub device: String,
+    /// When the reading was taken off the device.
+    pub timestamp: Instant,
+    pub horizontal: f32,
+    pub vertical: f32,
+    pub buttons: HashSet<Button>,
+    /// IMU samples taken since the previous reading, oldest first. Empty for
+    /// controllers without motion sensors.
+    pub motion: Vec<MotionSample>,
+}
+
+/// One reading of a controller's IMU, in the controller's own axes.
+#[derive(Debug, Clone, Copy)]
+pub struct MotionSample {
+    /// Acceleration in g.
+    pub accel: [f32; 3],
+    /// Angular rate in degrees per second.
+    pub gyro: [f32; 3],
+}
+
+#[derive(Debug)]
+pub enum InputError {
+    /// The controller went away for good, e.g. the user quit.
+    Closed,
+    Device(String),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Closed => write!(f, "input closed"),
+            InputError::Device(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// How often a controller that only reports changes is read anyway, so a
+/// stick held still keeps the car's commands coming. Well inside the car
+/// thread's command timeout.
+pub const REPEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Anything that can drive the car: a gamepad, a JoyCon, a keyboard...
+pub trait InputSource: Send {
+    /// Blocks until the controller has a new reading, or for no longer than
+    /// `REPEAT_INTERVAL` for controllers that only report changes.
+    fn read_input(&mut self) -> Result<ControllerInput, InputError>;
+
+    /// The button layout used to arm, disarm and pick a direction.
+    fn bindings(&self) -> &'static Bindings;
+
+    /// Lets the person holding the controller know something happened, for
+    /// controllers that are able to.
+    fn feedback(&mut self, _event: FeedbackEvent) {}
+
+    /// Shows the controller's status on its lights, for controllers that have them.
+    fn indicate(&mut self, _lights: PlayerLights) {}
+}
+
+impl<S: InputSource + ?Sized> InputSource for Box<S> {
+    fn read_input(&mut self) -> Result<ControllerInput, InputError> {
+        (**self).read_input()
+    }
+
+    fn bindings(&self) -> &'static Bindings {
+        (**self).bindings()
+    }
+
+    fn feedback(&mut self, event: FeedbackEvent) {
+        (**self).feedback(event)
+    }
+
+    fn indicate(&mut self, lights: PlayerLights) {
+        (**self).indicate(lights)
+    }
+}
+
+/// Whether `fd` has something to read, waiting no longer than `timeout`.
+pub fn wait_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(e),
+            }
+        }
+        ready => Ok(ready > 0),
+    }
+}
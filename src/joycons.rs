@@ -1,31 +1,215 @@
-use crate::utils::map_range;
+use std::collections::HashSet;
+use std::time::Instant;
 
-#[derive(Debug, Clone)]
+use joycon_rs::prelude::{
+    input_report_mode::{standard_full_mode::AxisData, PushedButtons},
+    lights::*,
+    *,
+};
+use log::warn;
+
+use crate::controls::{Bindings, Tap, LEFT_JOYCON, RIGHT_JOYCON};
+use crate::drive_mode::{DriveMode, ModeEvent};
+use crate::feedback::{FeedbackEvent, PlayerLights, RumblePlayer};
+use crate::input::{Button, ControllerInput, InputError, InputSource, MotionSample};
+use crate::state_manager::Slot;
+
+#[derive(Debug, Clone, Default)]
 pub struct JoyConState {
-    pub forward: bool,
-    pub armed: bool,
+    pub mode: DriveMode,
+    /// The mode change the buttons asked for last reading, they only count
+    /// when first pressed.
+    pub action_held: Option<ModeEvent>,
+    /// Handover counts when tapped, it shares a button with emergency stop.
+    pub handover: Tap,
+    /// Whether the takeover buttons are held, for the instructor in trainer mode.
+    pub takeover: bool,
+    /// Switching profile counts when tapped, its chord shares a button with
+    /// handover.
+    pub profile: Tap,
+    /// The throttle last reading, so arming from elsewhere can check it is
+    /// centred.
+    pub throttle: f32,
+}
+
+/// Raw stick travel measured on our JoyCons, as (min, max) for each axis.
+struct StickRange {
+    horizontal: (u16, u16),
+    vertical: (u16, u16),
 }
 
-pub fn remap_left_joycon(horizontal: u16, vertical: u16, forward: bool) -> (u16, u16) {
+const LEFT_STICK: StickRange = StickRange {
     // horizontal min (left) 670
     // horizotal max (right) 3420
-    let horizontal_mapped = map_range(horizontal, (670, 3240), (240, 1807), forward);
-
+    horizontal: (670, 3240),
     // vertical min (down) 1080
     // vertical max (up) 3240
-    let vertical_mapped = map_range(vertical, (1080, 3240), (240, 1807), false);
-
-    (horizontal_mapped, vertical_mapped)
-}
+    vertical: (1080, 3240),
+};
 
-pub fn remap_right_joycon(horizontal: u16, vertical: u16, forward: bool) -> (u16, u16) {
+const RIGHT_STICK: StickRange = StickRange {
     // horizontal min (left) 700
     // horizotal max (right) 3600
-    let horizontal_mapped = map_range(horizontal, (700, 3600), (240, 1807), forward);
-
+    horizontal: (700, 3600),
     // vertical min (down) 780
     // vertical max (up) 3000
-    let vertical_mapped = map_range(vertical, (780, 3000), (240, 1807), false);
+    vertical: (780, 3000),
+};
+
+/// Maps a raw stick reading onto -1.0..1.0, clamping anything outside of the range.
+fn normalize(value: u16, (min, max): (u16, u16)) -> f32 {
+    if min == max {
+        return 0.0;
+    }
+
+    let value = value.clamp(min, max);
+    (value - min) as f32 / (max - min) as f32 * 2.0 - 1.0
+}
+
+// Scale of the raw IMU readings at the sensitivities joycon-rs sets up (±8G, ±2000dps)
+const ACCEL_G_PER_LSB: f32 = 16.0 / 65535.0;
+const GYRO_DPS_PER_LSB: f32 = 4000.0 / 65535.0;
+
+fn motion_sample(axis: &AxisData) -> MotionSample {
+    MotionSample {
+        accel: [
+            axis.accel_x as f32 * ACCEL_G_PER_LSB,
+            axis.accel_y as f32 * ACCEL_G_PER_LSB,
+            axis.accel_z as f32 * ACCEL_G_PER_LSB,
+        ],
+        gyro: [
+            axis.gyro_1 as f32 * GYRO_DPS_PER_LSB,
+            axis.gyro_2 as f32 * GYRO_DPS_PER_LSB,
+            axis.gyro_3 as f32 * GYRO_DPS_PER_LSB,
+        ],
+    }
+}
+
+/// Translates the buttons of a JoyCon report into the buttons the control logic understands.
+fn joycon_buttons(pushed: &PushedButtons) -> HashSet<Button> {
+    [
+        (Buttons::Up, Button::Up),
+        (Buttons::Down, Button::Down),
+        (Buttons::Left, Button::Left),
+        (Buttons::Right, Button::Right),
+        (Buttons::A, Button::A),
+        (Buttons::B, Button::B),
+        (Buttons::X, Button::X),
+        (Buttons::Y, Button::Y),
+        (Buttons::L, Button::L),
+        (Buttons::R, Button::R),
+        (Buttons::ZL, Button::ZL),
+        (Buttons::ZR, Button::ZR),
+        (Buttons::SL, Button::SL),
+        (Buttons::SR, Button::SR),
+        (Buttons::Plus, Button::Plus),
+        (Buttons::Minus, Button::Minus),
+        (Buttons::Home, Button::Home),
+        (Buttons::Capture, Button::Capture),
+    ]
+    .into_iter()
+    .filter(|(joycon_button, _)| pushed.contains(*joycon_button))
+    .map(|(_, button)| button)
+    .collect()
+}
+
+/// A JoyCon in standard full mode, reporting at 60Hz.
+pub struct JoyConInput {
+    mode: StandardFullMode<SimpleJoyConDriver>,
+    device_type: JoyConDeviceType,
+    device: String,
+    rumble: RumblePlayer,
+}
+
+impl JoyConInput {
+    pub fn new(driver: SimpleJoyConDriver) -> JoyConResult<JoyConInput> {
+        let (device_type, serial_number) = {
+            let joycon = driver.joycon();
+            (joycon.device_type(), joycon.serial_number().to_owned())
+        };
+
+        Ok(JoyConInput {
+            mode: StandardFullMode::new(driver)?,
+            device_type,
+            device: format!("joycon-{}", serial_number),
+            rumble: RumblePlayer::default(),
+        })
+    }
+
+    pub fn device_type(&self) -> &JoyConDeviceType {
+        &self.device_type
+    }
+
+    /// The slot a JoyCon drives is decided by which hand it is for.
+    pub fn slot(&self) -> Option<Slot> {
+        match self.device_type {
+            JoyConDeviceType::JoyConL => Some(Slot::Left),
+            JoyConDeviceType::JoyConR => Some(Slot::Right),
+            _ => None,
+        }
+    }
+}
+
+impl InputSource for JoyConInput {
+    fn read_input(&mut self) -> Result<ControllerInput, InputError> {
+        let report = self
+            .mode
+            .read_input_report()
+            .map_err(|e| InputError::Device(format!("{}: {:?}", self.device, e)))?;
+        // Before the rumble, which can block on the write
+        let read = Instant::now();
+
+        if let Some(pulse) = self.rumble.tick(read) {
+            let rumble = Rumble::new(pulse.frequency, pulse.amplitude);
+            if let Err(e) = self.mode.driver_mut().rumble((Some(rumble), Some(rumble))) {
+                warn!("{}: rumble failed: {:?}", self.device, e);
+            }
+        }
+
+        let (stick, range) = match self.device_type {
+            JoyConDeviceType::JoyConR => (&report.common.right_analog_stick_data, &RIGHT_STICK),
+            _ => (&report.common.left_analog_stick_data, &LEFT_STICK),
+        };
+
+        Ok(ControllerInput {
+            device: self.device.clone(),
+            timestamp: read,
+            horizontal: normalize(stick.horizontal, range.horizontal),
+            vertical: normalize(stick.vertical, range.vertical),
+            buttons: joycon_buttons(&report.common.pushed_buttons),
+            // Each report carries three samples 5ms apart, newest first
+            motion: report.extra.data.iter().rev().map(motion_sample).collect(),
+        })
+    }
+
+    fn bindings(&self) -> &'static Bindings {
+        match self.device_type {
+            JoyConDeviceType::JoyConR => &RIGHT_JOYCON,
+            _ => &LEFT_JOYCON,
+        }
+    }
+
+    fn feedback(&mut self, event: FeedbackEvent) {
+        self.rumble.play(event);
+    }
+
+    fn indicate(&mut self, lights: PlayerLights) {
+        const LIGHT_UP: [LightUp; 4] = [LightUp::LED0, LightUp::LED1, LightUp::LED2, LightUp::LED3];
+        const FLASH: [Flash; 4] = [Flash::LED0, Flash::LED1, Flash::LED2, Flash::LED3];
+
+        let light_up: Vec<LightUp> = LIGHT_UP
+            .into_iter()
+            .zip(lights.steady)
+            .filter_map(|(led, on)| on.then_some(led))
+            .collect();
+        let flash: Vec<Flash> = FLASH
+            .into_iter()
+            .zip(lights.flashing)
+            .filter_map(|(led, on)| on.then_some(led))
+            .collect();
 
-    (horizontal_mapped, vertical_mapped)
+        if let Err(e) = self.mode.driver_mut().set_player_lights(&light_up, &flash) {
+            warn!("{}: setting player lights failed: {:?}", self.device, e);
+        }
+    }
 }
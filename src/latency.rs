@@ -0,0 +1,303 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Each power of two of microseconds is split into this many buckets, so a
+// percentile is out by a sixteenth at most
+const SUB_BUCKETS: u64 = 8;
+// Anything slower than 2^30µs, about 18 minutes, lands in the last bucket
+const MAX_POWER: u32 = 30;
+const BUCKETS: usize = ((MAX_POWER - 1) as u64 * SUB_BUCKETS) as usize;
+// How often the percentiles are worked out, logged and started over
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// When a controller reading was taken and turned into a command, carried
+/// along with the command to the car.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// When the report was read off the controller.
+    pub read: Instant,
+    /// When the reading had been mapped to a command.
+    pub mapped: Instant,
+}
+
+/// Counts durations in buckets rather than keeping every one, so it can
+/// take every frame for as long as it runs.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; BUCKETS],
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.counts[bucket(micros)] += 1;
+        self.total += 1;
+    }
+
+    /// The duration `fraction` of the recorded ones are at or under, give or
+    /// take a bucket. Nothing if none have been recorded.
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        let rank = ((fraction * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.counts.iter().enumerate().find_map(|(index, count)| {
+            seen += count;
+            (seen >= rank).then(|| Duration::from_micros(bucket_middle(index)))
+        })
+    }
+
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        Some(Percentiles {
+            count: self.total,
+            p50: self.percentile(0.50)?,
+            p95: self.percentile(0.95)?,
+            p99: self.percentile(0.99)?,
+        })
+    }
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let power = 63 - micros.leading_zeros();
+    if power > MAX_POWER {
+        return BUCKETS - 1;
+    }
+    let sub = (micros >> (power - 3)) & (SUB_BUCKETS - 1);
+    ((power - 2) as u64 * SUB_BUCKETS + sub) as usize
+}
+
+/// The duration in the middle of bucket `index`.
+fn bucket_middle(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let power = index / SUB_BUCKETS + 2;
+    let width = 1 << (power - 3);
+    (SUB_BUCKETS + index % SUB_BUCKETS) * width + width / 2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "p50 {:.1}ms p95 {:.1}ms p99 {:.1}ms",
+            self.p50.as_secs_f64() * 1000.0,
+            self.p95.as_secs_f64() * 1000.0,
+            self.p99.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// How long each stage from controller to car took over the last report
+/// interval. A stage is missing if nothing went through it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyReport {
+    /// Report read to command mapped, on the controller's thread.
+    pub mapping: Option<Percentiles>,
+    /// Command mapped to the frame being sent, waiting on the car thread and
+    /// the frame cadence.
+    pub queue: Option<Percentiles>,
+    /// Handing the frame to the link until it is written, for every frame.
+    pub write: Option<Percentiles>,
+    /// Report read to frame written, the whole way.
+    pub total: Option<Percentiles>,
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stages = [
+            ("mapping", self.mapping),
+            ("queue", self.queue),
+            ("write", self.write),
+            ("total", self.total),
+        ];
+        let parts: Vec<String> = stages
+            .iter()
+            .filter_map(|(name, percentiles)| {
+                percentiles.map(|percentiles| format!("{} {}", name, percentiles))
+            })
+            .collect();
+
+        if parts.is_empty() {
+            write!(f, "nothing measured")
+        } else {
+            write!(f, "{}", parts.join(" | "))
+        }
+    }
+}
+
+/// Times every frame on its way to the car, a report at a time.
+pub struct LatencyTracker {
+    mapping: Histogram,
+    queue: Histogram,
+    write: Histogram,
+    total: Histogram,
+    since: Instant,
+}
+
+impl LatencyTracker {
+    pub fn new() -> LatencyTracker {
+        LatencyTracker {
+            mapping: Histogram::new(),
+            queue: Histogram::new(),
+            write: Histogram::new(),
+            total: Histogram::new(),
+            since: Instant::now(),
+        }
+    }
+
+    /// Takes the times of one frame, handed to the link at `sent` and
+    /// written by `written`. `timing` only comes with the first frame for a
+    /// command, repeats of it aren't a fresh reading.
+    pub fn record(&mut self, timing: Option<Timing>, sent: Instant, written: Instant) {
+        self.write.record(written.saturating_duration_since(sent));
+        if let Some(timing) = timing {
+            self.mapping
+                .record(timing.mapped.saturating_duration_since(timing.read));
+            self.queue
+                .record(sent.saturating_duration_since(timing.mapped));
+            self.total
+                .record(written.saturating_duration_since(timing.read));
+        }
+    }
+
+    /// The percentiles since the last report, once one is due. Each report
+    /// starts over, so a regression shows up rather than being averaged away.
+    pub fn report(&mut self, now: Instant) -> Option<LatencyReport> {
+        if now.saturating_duration_since(self.since) < REPORT_INTERVAL {
+            return None;
+        }
+
+        let report = LatencyReport {
+            mapping: self.mapping.percentiles(),
+            queue: self.queue.percentiles(),
+            write: self.write.percentiles(),
+            total: self.total.percentiles(),
+        };
+        *self = LatencyTracker {
+            since: now,
+            ..LatencyTracker::new()
+        };
+        Some(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_hold_their_middle() {
+        for index in 0..BUCKETS {
+            assert_eq!(bucket(bucket_middle(index)), index, "bucket {}", index);
+        }
+    }
+
+    #[test]
+    fn buckets_are_in_order_and_within_a_sixteenth() {
+        let mut last = 0;
+        for micros in (0..100_000).chain([1 << 20, (1 << 30) - 1]) {
+            let index = bucket(micros);
+            assert!(index >= last, "{}µs went back a bucket", micros);
+            last = index;
+
+            let middle = bucket_middle(index) as f64;
+            let error = (middle - micros as f64).abs() / micros.max(1) as f64;
+            assert!(error <= 1.0 / 16.0, "{}µs reported as {}µs", micros, middle);
+        }
+    }
+
+    #[test]
+    fn slow_durations_land_in_the_last_bucket() {
+        assert_eq!(bucket(1 << 31), BUCKETS - 1);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    fn close_to(duration: Duration, micros: u64) -> bool {
+        let error = (duration.as_micros() as f64 - micros as f64).abs();
+        error <= micros as f64 / 16.0
+    }
+
+    #[test]
+    fn percentiles_of_a_uniform_spread() {
+        let mut histogram = Histogram::new();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        let percentiles = histogram.percentiles().unwrap();
+        assert_eq!(percentiles.count, 1000);
+        assert!(close_to(percentiles.p50, 500), "{:?}", percentiles.p50);
+        assert!(close_to(percentiles.p95, 950), "{:?}", percentiles.p95);
+        assert!(close_to(percentiles.p99, 990), "{:?}", percentiles.p99);
+    }
+
+    #[test]
+    fn percentiles_see_the_tail() {
+        let mut histogram = Histogram::new();
+        for _ in 0..98 {
+            histogram.record(Duration::from_millis(2));
+        }
+        histogram.record(Duration::from_millis(40));
+        histogram.record(Duration::from_millis(40));
+
+        assert!(close_to(histogram.percentile(0.50).unwrap(), 2_000));
+        assert!(close_to(histogram.percentile(0.98).unwrap(), 2_000));
+        assert!(close_to(histogram.percentile(0.99).unwrap(), 40_000));
+        assert!(close_to(histogram.percentile(1.0).unwrap(), 40_000));
+    }
+
+    #[test]
+    fn nothing_recorded_has_no_percentiles() {
+        assert_eq!(Histogram::new().percentile(0.5), None);
+        assert_eq!(Histogram::new().percentiles(), None);
+    }
+
+    #[test]
+    fn tracker_reports_each_interval_afresh() {
+        let start = Instant::now();
+        let mut tracker = LatencyTracker::new();
+        let timing = Timing {
+            read: start,
+            mapped: start + Duration::from_millis(1),
+        };
+        tracker.record(
+            Some(timing),
+            start + Duration::from_millis(3),
+            start + Duration::from_millis(4),
+        );
+        tracker.record(
+            None,
+            start + Duration::from_millis(10),
+            start + Duration::from_millis(11),
+        );
+
+        assert_eq!(tracker.report(tracker.since), None);
+        let report = tracker.report(tracker.since + REPORT_INTERVAL).unwrap();
+        assert_eq!(report.write.unwrap().count, 2);
+        assert_eq!(report.total.unwrap().count, 1);
+        assert!(close_to(report.total.unwrap().p50, 4_000));
+        assert!(close_to(report.queue.unwrap().p50, 2_000));
+
+        let report = tracker.report(tracker.since + REPORT_INTERVAL).unwrap();
+        assert_eq!(report, LatencyReport::default());
+    }
+}
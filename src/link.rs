@@ -0,0 +1,87 @@
+use std::io::{self, Read};
+
+use log::debug;
+use sbus_codec::{Flags, Variant};
+
+use crate::latency::LatencyReport;
+use crate::sbus_writer::SBusWriter;
+use crate::telemetry::MockTelemetry;
+
+/// Where SBUS frames go: the car's serial port, or something standing in for it.
+pub trait Link: Send {
+    /// Writes one SBUS frame carrying `channels`, see `build_channels`.
+    fn send_channels(&mut self, channels: [u16; 16]) -> io::Result<()>;
+
+    /// Like `send_channels`, for a frame that only repeats the last command
+    /// to keep up the frame cadence, rather than a fresh one from a controller.
+    fn repeat_channels(&mut self, channels: [u16; 16]) -> io::Result<()> {
+        self.send_channels(channels)
+    }
+
+    /// Where the car's telemetry comes back, if this link carries any. SBUS
+    /// itself only goes one way.
+    fn telemetry(&mut self) -> Option<Box<dyn Read + Send>> {
+        None
+    }
+}
+
+/// How the link to the car has been doing, for anyone watching. Only the car
+/// thread writes it.
+#[derive(Debug, Clone, Default)]
+pub struct LinkStats {
+    pub frames: u64,
+    pub failures: u64,
+    /// The steering and throttle asked for last, before the profile limits.
+    pub command: Option<(u16, u16)>,
+    /// The channels sent last.
+    pub channels: Option<[u16; 16]>,
+    /// Why the last frame failed, if it did.
+    pub error: Option<String>,
+    /// How long frames took to get to the car, as of the last report.
+    pub latency: LatencyReport,
+}
+
+impl LinkStats {
+    pub fn record(&mut self, command: (u16, u16), channels: [u16; 16], result: &io::Result<()>) {
+        self.frames += 1;
+        self.command = Some(command);
+        self.channels = Some(channels);
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        if self.error.is_some() {
+            self.failures += 1;
+        }
+    }
+}
+
+/// Stands in for the car on the bench and in replays. Frames are only logged,
+/// and canned telemetry comes back.
+pub struct MockLink {
+    writer: SBusWriter,
+}
+
+impl MockLink {
+    pub fn new(variant: Variant) -> MockLink {
+        MockLink {
+            writer: SBusWriter::new(variant),
+        }
+    }
+}
+
+impl Link for MockLink {
+    fn send_channels(&mut self, channels: [u16; 16]) -> io::Result<()> {
+        let packet = self.writer.encode(&channels, Flags::default());
+        debug!(
+            "Mock frame: {}",
+            packet
+                .iter()
+                .map(|&v| format!("{:02X}", v))
+                .collect::<Vec<String>>()
+                .join("")
+        );
+        Ok(())
+    }
+
+    fn telemetry(&mut self) -> Option<Box<dyn Read + Send>> {
+        Some(Box::new(MockTelemetry::new()))
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::tui::Dashboard;
+
+/// Prints log records to stderr, one per line, as text or JSON.
+struct Logger {
+    level: LevelFilter,
+    json: bool,
+    /// Takes the records instead of stderr while it is on screen.
+    dashboard: Option<Dashboard>,
+}
+
+/// A UTC timestamp like `2024-05-01T12:30:05.123Z`, without pulling in a
+/// date crate for it.
+fn timestamp() -> String {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, from Howard Hinnant's date algorithms
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Targets are module paths, the crate name is the same on every line
+fn short_target(target: &str) -> &str {
+    target.strip_prefix("glorb_control::").unwrap_or(target)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = if self.json {
+            serde_json::json!({
+                "time": timestamp(),
+                "level": record.level().as_str(),
+                "target": short_target(record.target()),
+                "message": record.args().to_string(),
+            })
+            .to_string()
+        } else {
+            format!(
+                "{} {:5} {}: {}",
+                timestamp(),
+                record.level(),
+                short_target(record.target()),
+                record.args()
+            )
+        };
+
+        match &self.dashboard {
+            Some(dashboard) => dashboard.event(line),
+            None => {
+                let _ = writeln!(std::io::stderr().lock(), "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Sends everything logged at `level` or above to stderr, or to the
+/// dashboard if there is one. Call once, at start up.
+pub fn init(level: LevelFilter, json: bool, dashboard: Option<Dashboard>) {
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        json,
+        dashboard,
+    }))
+    .expect("Logger already initialised");
+    log::set_max_level(level);
+}
@@ -1,203 +1,351 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 use arc_swap::ArcSwap;
 use joycon_rs::prelude::*;
+use log::{error, info, trace, warn};
+use sbus_codec::Variant;
 
-// mod sbus_parser;
-// use sbus_parser::SBusPacketParser;
-
+mod sbus_parser;
 mod sbus_writer;
 
 mod car;
 #[cfg(feature = "car")]
 use car::Car;
-use car::CarCommand;
+use car::{build_channels, CarCommand};
+
+mod link;
+use link::{Link, LinkStats, MockLink};
 
 mod utils;
-use utils::mix_joycon_states;
+use utils::map_axis;
 
 mod joycons;
-use joycons::{remap_left_joycon, remap_right_joycon};
+use joycons::JoyConInput;
+
+mod arbitration;
+
+mod drive_mode;
+
+mod battery;
+use battery::BatteryGuard;
+
+mod latency;
+use latency::LatencyTracker;
 
 mod state_manager;
 use state_manager::StateManager;
 
-fn main() {
-    // Create a channel for sending commands
-    let (car_tx, car_rx) = mpsc::channel();
+mod input;
+use input::InputSource;
 
-    //  Spawn a dedicated thread that owns `car`
-    let _car_handle = thread::spawn(move || {
-        #[cfg(feature = "car")]
-        let mut car = Car::new();
-        for command in car_rx {
-            println!("Sending command to car: {:?}", command);
-            match command {
-                CarCommand::SendData(horizontal_mapped, vertical_mapped, forward, armed) => {
-                    #[cfg(feature = "car")]
-                    car.send_data(horizontal_mapped, vertical_mapped, forward, armed);
-                } // Handle other commands as needed
-            }
-        }
-    });
+mod controls;
 
-    let manager = JoyConManager::get_instance();
-    let (managed_devices, new_devices) = {
-        let lock = manager.lock();
-        match lock {
-            Ok(m) => (m.managed_devices(), m.new_devices()),
-            Err(_) => unreachable!(),
-        }
-    };
+mod feedback;
 
-    let state_store = Arc::new(ArcSwap::from(Arc::new(StateManager::new())));
+#[cfg(feature = "gamepad")]
+mod gamepad;
 
-    managed_devices
-        .into_iter()
-        .chain(new_devices)
-        .flat_map(|dev| SimpleJoyConDriver::new(&dev))
-        .try_for_each::<_, JoyConResult<()>>(|driver| {
-            let device_type = driver.joycon().device_type();
+mod terminal;
 
-            println!("Found JoyCon: {:?}", device_type);
+mod tilt;
+use tilt::{TiltConfig, TiltInput};
 
-            let car_tx_clone = car_tx.clone();
-            let state_store = state_store.clone();
+mod profiles;
+use profiles::Limiter;
 
-            // Change JoyCon to Simple hid mode.
-            // let simple_hid_mode = SimpleHIDMode::new(driver)?;
-            let standard_full_mode = StandardFullMode::new(driver)?;
+mod logger;
 
-            let device_type = Arc::new(device_type);
+mod recorder;
+use recorder::{Recorder, RecordingInput};
 
-            // Spawn thread
-            thread::spawn(move || {
-                loop {
-                    // Forward the report to the car thread
-                    let report = standard_full_mode.read_input_report();
-
-                    match report {
-                        Ok(report) => {
-                            // println!("{:?}", report);
-                            let state_arc = state_store.load_full();
-                            let mut state = (*state_arc).clone();
-
-                            match *device_type {
-                                JoyConDeviceType::JoyConL => {
-                                    // left joycon buttons
-
-                                    if report.common.pushed_buttons.contains(Buttons::Down) {
-                                        println!("Down pressed");
-                                        state.l.forward = false;
-                                    } else if report.common.pushed_buttons.contains(Buttons::Up) {
-                                        println!("Up pressed");
-                                        state.l.forward = true;
-                                    } else if report.common.pushed_buttons.contains(Buttons::Left)
-                                        && report.common.pushed_buttons.contains(Buttons::Right)
-                                    {
-                                        println!("Left and right pressed - armed");
-                                        state.l.armed = true;
-                                    } else if report.common.pushed_buttons.contains(Buttons::SL)
-                                        && report.common.pushed_buttons.contains(Buttons::SR)
-                                    {
-                                        println!("L and R pressed - unarmed");
-                                        state.l.armed = false;
-                                    }
-
-                                    let (forward, armed) = mix_joycon_states(&state);
-
-                                    let (horizontal_mapped, vertical_mapped) = remap_left_joycon(
-                                        report.common.left_analog_stick_data.horizontal,
-                                        report.common.left_analog_stick_data.vertical,
-                                        state.l.forward,
-                                    );
-
-                                    if state.l.armed && !state.r.armed {
-                                        car_tx_clone
-                                            .send(CarCommand::SendData(
-                                                horizontal_mapped,
-                                                vertical_mapped,
-                                                forward,
-                                                armed,
-                                            ))
-                                            .unwrap();
-                                    }
-
-                                    state.set_state(
-                                        (*device_type).clone(),
-                                        state.l.forward,
-                                        state.l.armed,
-                                    );
-                                }
-                                JoyConDeviceType::JoyConR => {
-                                    // right joycon buttons
-                                    if report.common.pushed_buttons.contains(Buttons::B) {
-                                        println!("B pressed");
-                                        state.r.forward = false;
-                                    } else if report.common.pushed_buttons.contains(Buttons::X) {
-                                        println!("X pressed");
-                                        state.r.forward = true;
-                                    } else if report.common.pushed_buttons.contains(Buttons::Y)
-                                        && report.common.pushed_buttons.contains(Buttons::A)
-                                    {
-                                        println!("Y and A pressed - armed");
-                                        state.r.armed = true;
-                                    } else if report.common.pushed_buttons.contains(Buttons::SL)
-                                        && report.common.pushed_buttons.contains(Buttons::SR)
-                                    {
-                                        println!("L and R pressed - unarmed");
-                                        state.r.armed = false;
-                                    }
-
-                                    let (horizontal_mapped, vertical_mapped) = remap_right_joycon(
-                                        report.common.right_analog_stick_data.horizontal,
-                                        report.common.right_analog_stick_data.vertical,
-                                        state.r.forward,
-                                    );
-
-                                    // println!(
-                                    //     "Left joycon sending Horizontal: {}\t Vertical: {}\t forward: {}\t armed: {}\t joycon_states: {:?}",
-                                    //     horizontal_mapped, vertical_mapped, forward, armed, state
-                                    // );
-                                    let (forward, armed) = mix_joycon_states(&state);
-
-                                    if state.r.armed && !state.l.armed {
-                                        car_tx_clone
-                                            .send(CarCommand::SendData(
-                                                horizontal_mapped,
-                                                vertical_mapped,
-                                                forward,
-                                                armed,
-                                            ))
-                                            .unwrap();
-                                    }
-
-                                    state.set_state(
-                                        (*device_type).clone(),
-                                        state.r.forward,
-                                        state.r.armed,
-                                    );
-                                }
-                                _ => {
-                                    println!("Unknown JoyCon type {:?}", device_type)
-                                }
-                            };
-
-                            state_store.store(Arc::new(state));
-                        }
-                        Err(e) => {
-                            println!("Error: {:?}", e);
+mod replay;
+
+mod monitor;
+
+mod proxy;
+
+mod remote;
+use remote::UdpLink;
+
+mod secure;
+
+mod telemetry;
+
+mod tui;
+use tui::{Dashboard, DashboardInput};
+
+mod api;
+
+mod cli;
+use cli::{Command, Options};
+
+// The car stops once the latest command is this old. Every controller sends
+// commands far more often than this, even held still
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(250);
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let dashboard = options.tui.then(Dashboard::new);
+    logger::init(options.log_level, options.log_json, dashboard.clone());
+
+    // The monitor only listens, it doesn't drive anything
+    if let Command::Monitor(port_name) = &options.command {
+        monitor::run_monitor(port_name, options.sbus);
+        return;
+    }
+
+    // The vehicle side only passes on what the controller side sends
+    if let Command::Vehicle(address) = &options.command {
+        remote::run_vehicle(
+            address,
+            options.key.as_deref().expect("the CLI asks for a key"),
+            open_link(options.mock_link, options.sbus),
+            options.sbus.frame_interval(),
+            options.max_latency,
+        );
+        return;
+    }
+
+    let recording = match options.record.as_deref().map(Recorder::create).transpose() {
+        Ok(recording) => recording,
+        Err(e) => {
+            log::error!("Failed to create the recording: {}", e);
+            return;
+        }
+    };
+    let (recorder, recorder_handle) = recording.unzip();
+
+    let mut state = StateManager::new(options.policy);
+    state.student_throttle = options.student_throttle;
+    state.profile = options.profile;
+    state.battery = BatteryGuard::new(options.battery);
+    let state_store = Arc::new(ArcSwap::from(Arc::new(state)));
+    let link_stats = Arc::new(ArcSwap::from_pointee(LinkStats::default()));
+    if let Some(dashboard) = &dashboard {
+        dashboard.start(state_store.clone(), link_stats.clone());
+    }
+    if let Some(address) = &options.api {
+        if let Err(e) = api::start_api(
+            address,
+            options.api_token.clone(),
+            state_store.clone(),
+            link_stats.clone(),
+        ) {
+            error!("Failed to start the API on {}: {}", address, e);
+            return;
+        }
+    }
+
+    // Create a channel for sending commands
+    let (car_tx, car_rx) = mpsc::channel();
+
+    // In proxy mode the controllers' frames go to the proxy instead of the car
+    let mut link: Box<dyn Link> = match &options.command {
+        Command::Proxy { input, output } => {
+            match proxy::start_proxy(
+                input,
+                output,
+                options.sbus,
+                options.overridden.clone(),
+                state_store.clone(),
+            ) {
+                Ok(link) => Box::new(link),
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        }
+        _ => match &options.remote {
+            Some(address) => match UdpLink::connect(
+                address,
+                options.key.as_deref().expect("the CLI asks for a key"),
+            ) {
+                Ok(link) => Box::new(link),
+                Err(e) => {
+                    error!("Failed to reach the vehicle at {}: {}", address, e);
+                    return;
+                }
+            },
+            None => open_link(options.mock_link, options.sbus),
+        },
+    };
+
+    // Telemetry comes back on its own port if it has one, otherwise over the
+    // link
+    let telemetry = match &options.telemetry {
+        Some(port_name) => match telemetry::open_telemetry_port(port_name) {
+            Ok(port) => Some((port, port_name.clone())),
+            Err(e) => {
+                error!("Failed to open {}: {}", port_name, e);
+                return;
+            }
+        },
+        None => link
+            .telemetry()
+            .map(|reader| (reader, "the link".to_owned())),
+    };
+    if let Some((reader, name)) = telemetry {
+        telemetry::start_telemetry(reader, name, state_store.clone());
+    }
+
+    //  Spawn a dedicated thread that owns `car`
+    let car_state_store = state_store.clone();
+    let car_recorder = recorder.clone();
+    let car_link_stats = link_stats.clone();
+    let frame_interval = options.sbus.frame_interval();
+    let car_handle = thread::spawn(move || {
+        let mut limiter = Limiter::new();
+        let mut latency = LatencyTracker::new();
+        // Only logged when it changes, a link that is down fails every frame
+        let mut last_error: Option<String> = None;
+        // `fresh` is whether the command came from a controller since the
+        // last frame, rather than being repeated or made up here
+        let mut send = |command: CarCommand, fresh: bool| {
+            trace!("Sending command to car: {:?}", command);
+            match command {
+                CarCommand::SendData(
+                    horizontal_mapped,
+                    vertical_mapped,
+                    forward,
+                    armed,
+                    timing,
+                ) => {
+                    let state = car_state_store.load();
+                    let limits = state.battery.derate(state.profile.limits());
+                    let (horizontal_limited, vertical_limited) =
+                        limiter.apply(limits, horizontal_mapped, vertical_mapped, Instant::now());
+
+                    let channels =
+                        build_channels(horizontal_limited, vertical_limited, forward, armed);
+                    let sent = Instant::now();
+                    let result = if fresh {
+                        link.send_channels(channels)
+                    } else {
+                        link.repeat_channels(channels)
+                    };
+                    latency.record(timing, sent, Instant::now());
+                    let error = result.as_ref().err().map(|e| e.to_string());
+                    if error != last_error {
+                        match &error {
+                            Some(e) => error!("Sending frames failed: {}", e),
+                            None => info!("Sending frames again"),
                         }
+                        last_error = error;
+                    }
+                    if let Some(recorder) = &car_recorder {
+                        recorder.record_frame(channels, &result);
                     }
+                    let mut stats = (**car_link_stats.load()).clone();
+                    stats.record((horizontal_mapped, vertical_mapped), channels, &result);
+                    if let Some(report) = latency.report(Instant::now()) {
+                        info!("Latency: {}", report);
+                        stats.latency = report;
+                    }
+                    car_link_stats.store(Arc::new(stats));
+                    state_manager::set_link_ok(&car_state_store, result.is_ok());
+                } // Handle other commands as needed
+            }
+        };
+
+        // Once nobody is driving, say after a disarm from the API, the car
+        // stops even if no controller is around to send the command for it
+        let centre = map_axis(0.0, false);
+        let stopped = CarCommand::SendData(centre, centre, true, false, None);
+        let current = |command: CarCommand| match car_state_store.load().arbiter.owner() {
+            Some(_) => command,
+            None => stopped,
+        };
+
+        // Frames go out at the SBUS variant's cadence however often the
+        // controllers report, repeating the latest command in between. Once
+        // it gets stale the car is stopped instead, whoever sent it has
+        // stalled or dropped out
+        let mut command = match car_rx.recv() {
+            Ok(command) => command,
+            Err(_) => return,
+        };
+        let mut received = Instant::now();
+        let mut fresh = true;
+        let mut stale = false;
+        let mut next_frame = Instant::now();
+        loop {
+            if (received.elapsed() > COMMAND_TIMEOUT) != stale {
+                stale = !stale;
+                if stale {
+                    warn!("No commands for {:?}, stopping the car", COMMAND_TIMEOUT);
+                } else {
+                    info!("Commands coming in again");
                 }
-            });
+            }
+            if stale {
+                send(stopped, false);
+            } else {
+                send(current(command), fresh);
+            }
+            // Repeats of a command aren't a fresh reading to time
+            command = command.with_timing(None);
+            fresh = false;
+            next_frame = (next_frame + frame_interval).max(Instant::now());
+
+            loop {
+                match car_rx.recv_timeout(next_frame.saturating_duration_since(Instant::now())) {
+                    Ok(newer) => {
+                        command = newer;
+                        received = Instant::now();
+                        fresh = true;
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // Everything that sends commands has gone, whatever
+                        // the last one was, don't leave the car driving
+                        send(stopped, false);
+                        return;
+                    }
+                }
+            }
+        }
+    });
 
-            Ok(())
-        })
-        .unwrap();
+    match options.command {
+        Command::Drive | Command::Proxy { .. } => {
+            spawn_controllers(&state_store, &car_tx, options.tilt, recorder, dashboard)
+        }
+        Command::Keyboard => {
+            terminal::run_terminal(state_store, link_stats.clone(), car_tx.clone(), recorder)
+        }
+        Command::Replay(path) => replay::run_replay(
+            &path,
+            options.speed,
+            state_store,
+            car_tx.clone(),
+            recorder,
+            dashboard,
+        ),
+        Command::Monitor(_) | Command::Vehicle(_) => unreachable!(),
+    }
+
+    // The car thread runs until every input thread has gone away, and the
+    // recording until both have
+    drop(car_tx);
+    car_handle.join().unwrap();
+    if let Some(handle) = recorder_handle {
+        handle.join().unwrap();
+    }
 
     // println!("Printing all available hid devices:");
     // match HidApi::new() {
@@ -254,3 +402,108 @@ fn main() {
     // let parsed_packet = parser.try_parse().unwrap();
     // println!("{:?}", parsed_packet);
 }
+
+/// The car's serial port, unless asked for a mock link or built without the
+/// car feature.
+fn open_link(mock: bool, variant: Variant) -> Box<dyn Link> {
+    #[cfg(feature = "car")]
+    if !mock {
+        return Box::new(Car::new(variant));
+    }
+    #[cfg(not(feature = "car"))]
+    if !mock {
+        warn!("Built without the car feature, using a mock link");
+    }
+
+    Box::new(MockLink::new(variant))
+}
+
+/// Finds every JoyCon and gamepad and spawns a thread driving the car from each.
+fn spawn_controllers(
+    state_store: &Arc<ArcSwap<StateManager>>,
+    car_tx: &mpsc::Sender<CarCommand>,
+    tilt: Option<TiltConfig>,
+    recorder: Option<Recorder>,
+    dashboard: Option<Dashboard>,
+) {
+    let manager = JoyConManager::get_instance();
+    let (managed_devices, new_devices) = {
+        let lock = manager.lock();
+        match lock {
+            Ok(m) => (m.managed_devices(), m.new_devices()),
+            Err(_) => unreachable!(),
+        }
+    };
+
+    let mut claimed_slots = Vec::new();
+
+    managed_devices
+        .into_iter()
+        .chain(new_devices)
+        .flat_map(|dev| SimpleJoyConDriver::new(&dev))
+        .try_for_each::<_, JoyConResult<()>>(|driver| {
+            // Change JoyCon to Standard full mode.
+            let joycon = JoyConInput::new(driver)?;
+
+            info!("Found JoyCon: {:?}", joycon.device_type());
+
+            let slot = match joycon.slot() {
+                Some(slot) => slot,
+                None => {
+                    warn!("Unknown JoyCon type {:?}", joycon.device_type());
+                    return Ok(());
+                }
+            };
+            claimed_slots.push(slot);
+
+            let car_tx_clone = car_tx.clone();
+            let state_store = state_store.clone();
+
+            let mut source: Box<dyn InputSource> = match tilt {
+                Some(config) => Box::new(TiltInput::new(joycon, config)),
+                None => Box::new(joycon),
+            };
+            if let Some(recorder) = &recorder {
+                source = Box::new(RecordingInput::new(source, slot, recorder.clone()));
+            }
+            if let Some(dashboard) = &dashboard {
+                source = Box::new(DashboardInput::new(source, slot, dashboard.clone()));
+            }
+
+            // Spawn thread
+            thread::spawn(move || {
+                controls::run_input_source(source, slot, state_store, car_tx_clone)
+            });
+
+            Ok(())
+        })
+        .unwrap();
+
+    // Gamepads take whichever slots the JoyCons left free
+    #[cfg(feature = "gamepad")]
+    {
+        use state_manager::Slot;
+
+        let free_slots = [Slot::Left, Slot::Right]
+            .into_iter()
+            .filter(|slot| !claimed_slots.contains(slot));
+
+        for (gamepad, slot) in gamepad::discover_gamepads().into_iter().zip(free_slots) {
+            info!("Found gamepad: {} as {:?}", gamepad.name(), slot);
+
+            let mut source: Box<dyn InputSource> = Box::new(gamepad);
+            if let Some(recorder) = &recorder {
+                source = Box::new(RecordingInput::new(source, slot, recorder.clone()));
+            }
+            if let Some(dashboard) = &dashboard {
+                source = Box::new(DashboardInput::new(source, slot, dashboard.clone()));
+            }
+
+            let car_tx_clone = car_tx.clone();
+            let state_store = state_store.clone();
+            thread::spawn(move || {
+                controls::run_input_source(source, slot, state_store, car_tx_clone)
+            });
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use log::error;
+use sbus_codec::Variant;
+
+use crate::car::open_sbus_port;
+use crate::sbus_parser::{ParserStats, SBusPacket, SBusPacketParser};
+
+// How often the status line is redrawn, and the frame rate worked out
+const REFRESH: Duration = Duration::from_millis(250);
+
+fn millis(interval: Option<Duration>) -> String {
+    interval.map_or_else(
+        || "-".to_owned(),
+        |i| format!("{:.1}", i.as_secs_f32() * 1000.0),
+    )
+}
+
+fn print_status(packet: Option<&SBusPacket>, stats: &ParserStats, frame_rate: f32) {
+    let channels = match packet {
+        Some(packet) => packet
+            .channels
+            .iter()
+            .map(|c| format!("{:4}", c))
+            .collect::<Vec<String>>()
+            .join(" "),
+        None => "no frames yet".to_owned(),
+    };
+    let flag = |set: bool, name: &'static str| if set { name } else { "-" };
+    let flags = packet.map_or_else(String::new, |p| {
+        format!(
+            "{} {} {} {}",
+            flag(p.d1, "D1"),
+            flag(p.d2, "D2"),
+            flag(p.frame_lost, "LOST"),
+            flag(p.failsafe, "FAILSAFE")
+        )
+    });
+
+    print!(
+        "\r\x1b[2K{} | {} | {:5.1} fps, {}/{}/{} ms | frames {} lost {} failsafe {} resyncs {} discarded {}",
+        channels,
+        flags,
+        frame_rate,
+        millis(stats.min_interval),
+        millis(stats.mean_interval()),
+        millis(stats.max_interval),
+        stats.frames,
+        stats.frame_lost,
+        stats.failsafe,
+        stats.resyncs,
+        stats.bytes_discarded
+    );
+    let _ = io::stdout().flush();
+}
+
+/// Shows what is on the SBUS line at `port_name` until it fails: every
+/// channel, the flags and how well frames are getting through.
+pub fn run_monitor(port_name: &str, variant: Variant) {
+    let mut port = match open_sbus_port(port_name, variant) {
+        Ok(port) => port,
+        Err(e) => {
+            error!("Failed to open {}: {}", port_name, e);
+            return;
+        }
+    };
+
+    let mut parser = SBusPacketParser::new();
+    let mut last_packet = None;
+    let mut last_refresh = Instant::now();
+    let mut frames_at_refresh = 0;
+    // The parser holds two frames, so read no more than a frame at a time to
+    // never push out bytes it hasn't looked at
+    let mut buf = [0u8; 25];
+
+    loop {
+        match port.read(&mut buf) {
+            Ok(len) => parser.push_bytes(&buf[..len]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                println!();
+                error!("Reading {} failed: {}", port_name, e);
+                return;
+            }
+        }
+
+        while let Some(packet) = parser.try_parse() {
+            last_packet = Some(packet);
+        }
+
+        let elapsed = last_refresh.elapsed();
+        if elapsed >= REFRESH {
+            let stats = parser.stats();
+            let frame_rate = (stats.frames - frames_at_refresh) as f32 / elapsed.as_secs_f32();
+            frames_at_refresh = stats.frames;
+            last_refresh = Instant::now();
+            print_status(last_packet.as_ref(), stats, frame_rate);
+        }
+    }
+}
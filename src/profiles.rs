@@ -0,0 +1,290 @@
+use std::str::FromStr;
+use std::time::Instant;
+
+// Centre and half the travel of a mapped stick axis, see `map_axis`
+const CENTRE: f32 = 1023.5;
+const HALF_TRAVEL: f32 = 783.5;
+// Longest gap between frames the rates are worked out over, so the first
+// frame after a pause can't jump straight to full throttle. The very first
+// frame counts as coming after one
+const MAX_FRAME_GAP: f32 = 0.1;
+
+/// How hard the car is allowed to drive. Nothing is limited unless a
+/// driver, or `--profile`, picks one of the gentler profiles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    Beginner,
+    Normal,
+    #[default]
+    Sport,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Profile, String> {
+        match s {
+            "beginner" => Ok(Profile::Beginner),
+            "normal" => Ok(Profile::Normal),
+            "sport" => Ok(Profile::Sport),
+            _ => Err(format!("Unknown profile: {}", s)),
+        }
+    }
+}
+
+/// What a profile allows. Rates are in full stick travels (centre to end)
+/// per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Largest throttle allowed, 0.0..1.0.
+    pub max_throttle: f32,
+    /// How fast the throttle can move away from centre.
+    pub acceleration: f32,
+    /// How fast the throttle can move back towards centre.
+    pub deceleration: f32,
+    /// How fast the steering can move.
+    pub steering_rate: f32,
+}
+
+impl Profile {
+    pub fn limits(self) -> Limits {
+        match self {
+            Profile::Beginner => Limits {
+                max_throttle: 0.4,
+                acceleration: 0.5,
+                deceleration: 2.0,
+                steering_rate: 3.0,
+            },
+            Profile::Normal => Limits {
+                max_throttle: 0.7,
+                acceleration: 1.5,
+                deceleration: 4.0,
+                steering_rate: 6.0,
+            },
+            // Sport doesn't limit anything
+            Profile::Sport => Limits {
+                max_throttle: 1.0,
+                acceleration: f32::MAX,
+                deceleration: f32::MAX,
+                steering_rate: f32::MAX,
+            },
+        }
+    }
+
+    /// The profile the switch chord moves on to.
+    pub fn next(self) -> Profile {
+        match self {
+            Profile::Beginner => Profile::Normal,
+            Profile::Normal => Profile::Sport,
+            Profile::Sport => Profile::Beginner,
+        }
+    }
+}
+
+/// Moves `from` towards `to` by at most `max_step`.
+fn slew(from: f32, to: f32, max_step: f32) -> f32 {
+    from + (to - from).clamp(-max_step, max_step)
+}
+
+/// Applies a profile's limits to the mapped stick values on their way to the
+/// car. It remembers what it sent last, so it has to see every frame.
+pub struct Limiter {
+    // As offsets from centre, -1.0..1.0
+    horizontal: f32,
+    vertical: f32,
+    last_frame: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter {
+            horizontal: 0.0,
+            vertical: 0.0,
+            last_frame: None,
+        }
+    }
+
+    /// Limits one frame's `(horizontal, vertical)` channel values.
+    pub fn apply(
+        &mut self,
+        limits: Limits,
+        horizontal: u16,
+        vertical: u16,
+        now: Instant,
+    ) -> (u16, u16) {
+        let elapsed = self
+            .last_frame
+            .map_or(MAX_FRAME_GAP, |last| now.duration_since(last).as_secs_f32())
+            .min(MAX_FRAME_GAP);
+        self.last_frame = Some(now);
+
+        let target_horizontal = (horizontal as f32 - CENTRE) / HALF_TRAVEL;
+        let target_vertical = ((vertical as f32 - CENTRE) / HALF_TRAVEL)
+            .clamp(-limits.max_throttle, limits.max_throttle);
+
+        self.vertical = if self.vertical * target_vertical < 0.0 {
+            // Changing direction, slow down to centre first and only speed up
+            // the other way with whatever time is left
+            let stopping = self.vertical.abs() / limits.deceleration;
+            if stopping < elapsed {
+                slew(
+                    0.0,
+                    target_vertical,
+                    limits.acceleration * (elapsed - stopping),
+                )
+            } else {
+                slew(self.vertical, 0.0, limits.deceleration * elapsed)
+            }
+        } else if target_vertical.abs() < self.vertical.abs() {
+            slew(
+                self.vertical,
+                target_vertical,
+                limits.deceleration * elapsed,
+            )
+        } else {
+            slew(
+                self.vertical,
+                target_vertical,
+                limits.acceleration * elapsed,
+            )
+        };
+        self.horizontal = slew(
+            self.horizontal,
+            target_horizontal,
+            limits.steering_rate * elapsed,
+        );
+
+        (
+            (CENTRE + self.horizontal * HALF_TRAVEL).round() as u16,
+            (CENTRE + self.vertical * HALF_TRAVEL).round() as u16,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const FRAME: Duration = Duration::from_millis(10);
+
+    /// The channel value `offset` of the travel from centre.
+    fn channel(offset: f32) -> u16 {
+        (CENTRE + offset * HALF_TRAVEL).round() as u16
+    }
+
+    fn offset(channel: u16) -> f32 {
+        (channel as f32 - CENTRE) / HALF_TRAVEL
+    }
+
+    /// Feeds `frames` frames of a steady stick, returning the last output.
+    fn hold(
+        limiter: &mut Limiter,
+        profile: Profile,
+        stick: (f32, f32),
+        frames: u32,
+        now: &mut Instant,
+    ) -> (f32, f32) {
+        let mut out = (0, 0);
+        for _ in 0..frames {
+            *now += FRAME;
+            out = limiter.apply(profile.limits(), channel(stick.0), channel(stick.1), *now);
+        }
+        (offset(out.0), offset(out.1))
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.002
+    }
+
+    #[test]
+    fn sport_passes_everything_through() {
+        let mut limiter = Limiter::new();
+        let now = Instant::now();
+        // Including the very first frame
+        for (i, (horizontal, vertical)) in [(1807, 1807), (240, 240), (1023, 1807), (1807, 240)]
+            .into_iter()
+            .enumerate()
+        {
+            let at = now + FRAME * i as u32;
+            assert_eq!(
+                limiter.apply(Profile::Sport.limits(), horizontal, vertical, at),
+                (horizontal, vertical)
+            );
+        }
+    }
+
+    #[test]
+    fn throttle_is_clamped() {
+        let mut limiter = Limiter::new();
+        let mut now = Instant::now();
+        let (_, vertical) = hold(&mut limiter, Profile::Beginner, (0.0, 1.0), 500, &mut now);
+        assert!(close(vertical, 0.4));
+        let (_, vertical) = hold(&mut limiter, Profile::Beginner, (0.0, -1.0), 500, &mut now);
+        assert!(close(vertical, -0.4));
+    }
+
+    #[test]
+    fn throttle_and_steering_slew() {
+        let limits = Profile::Normal.limits();
+        let mut limiter = Limiter::new();
+        let mut now = Instant::now();
+        hold(&mut limiter, Profile::Normal, (0.0, 0.0), 1, &mut now);
+
+        // A tenth of a second of full stick only gets part of the way
+        let (horizontal, vertical) = hold(&mut limiter, Profile::Normal, (1.0, 1.0), 10, &mut now);
+        assert!(close(vertical, limits.acceleration * 0.1));
+        assert!(close(horizontal, limits.steering_rate * 0.1));
+
+        let (_, vertical) = hold(&mut limiter, Profile::Normal, (1.0, 1.0), 100, &mut now);
+        assert!(close(vertical, limits.max_throttle));
+
+        // Letting go comes back faster than it went out
+        let (_, vertical) = hold(&mut limiter, Profile::Normal, (1.0, 0.0), 10, &mut now);
+        assert!(close(
+            vertical,
+            limits.max_throttle - limits.deceleration * 0.1
+        ));
+    }
+
+    #[test]
+    fn changing_direction_slows_to_centre_first() {
+        let limits = Profile::Normal.limits();
+        let mut limiter = Limiter::new();
+        let mut now = Instant::now();
+        hold(&mut limiter, Profile::Normal, (0.0, 1.0), 100, &mut now);
+
+        // Slowing down goes at the deceleration rate until centre
+        let mut previous = limits.max_throttle;
+        let crossing = loop {
+            let (_, vertical) = hold(&mut limiter, Profile::Normal, (0.0, -1.0), 1, &mut now);
+            if vertical <= 0.0 {
+                break vertical;
+            }
+            assert!(close(previous - vertical, limits.deceleration * 0.01));
+            previous = vertical;
+        };
+
+        // and only the rest of that frame goes into speeding up the other way
+        let left_over = 0.01 - previous / limits.deceleration;
+        assert!(close(crossing, -limits.acceleration * left_over));
+        let (_, vertical) = hold(&mut limiter, Profile::Normal, (0.0, -1.0), 1, &mut now);
+        assert!(close(vertical - crossing, -limits.acceleration * 0.01));
+    }
+
+    #[test]
+    fn a_long_gap_counts_as_a_short_one() {
+        let limits = Profile::Beginner.limits();
+        let mut limiter = Limiter::new();
+        let now = Instant::now();
+        limiter.apply(limits, channel(0.0), channel(0.0), now);
+
+        let (_, vertical) = limiter.apply(
+            limits,
+            channel(0.0),
+            channel(1.0),
+            now + Duration::from_secs(5),
+        );
+        assert!(close(offset(vertical), limits.acceleration * MAX_FRAME_GAP));
+    }
+}
@@ -0,0 +1,242 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use log::{error, info};
+use sbus_codec::{Variant, FRAME_LEN};
+
+use crate::car::open_sbus_port;
+use crate::link::Link;
+use crate::sbus_parser::{SBusPacket, SBusPacketParser};
+use crate::sbus_writer::SBusWriter;
+use crate::state_manager::StateManager;
+
+// Once the controllers' last fresh command is this old they have gone quiet
+// and the receiver takes over again. Repeats of an old command to keep up the
+// frame cadence don't count
+const OVERRIDE_TIMEOUT: Duration = Duration::from_millis(100);
+
+struct Override {
+    channels: [u16; 16],
+    /// When the last fresh command came in.
+    at: Instant,
+}
+
+/// What the controllers' side and the proxy thread share.
+#[derive(Clone)]
+struct Shared {
+    latest: Arc<Mutex<Option<Override>>>,
+    output_ok: Arc<AtomicBool>,
+}
+
+impl Shared {
+    /// The controllers' channels, if they are driving and heard from
+    /// recently enough at `now` to override the receiver.
+    fn overriding(&self, driving: bool, now: Instant) -> Option<[u16; 16]> {
+        self.latest
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|o| driving && now.saturating_duration_since(o.at) < OVERRIDE_TIMEOUT)
+            .map(|o| o.channels)
+    }
+
+    fn output_result(&self) -> io::Result<()> {
+        // The frame only goes out with the next one from the receiver, so
+        // report how the output is doing instead
+        if self.output_ok.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(io::Error::other("proxy output is failing"))
+        }
+    }
+}
+
+/// Hands the controllers' frames to the proxy instead of a serial port.
+pub struct ProxyLink {
+    shared: Shared,
+}
+
+impl Link for ProxyLink {
+    fn send_channels(&mut self, channels: [u16; 16]) -> io::Result<()> {
+        *self.shared.latest.lock().unwrap() = Some(Override {
+            channels,
+            at: Instant::now(),
+        });
+        self.shared.output_result()
+    }
+
+    fn repeat_channels(&mut self, channels: [u16; 16]) -> io::Result<()> {
+        // Keeps the channels current without putting off the timeout
+        if let Some(latest) = self.shared.latest.lock().unwrap().as_mut() {
+            latest.channels = channels;
+        }
+        self.shared.output_result()
+    }
+}
+
+/// The receiver's frame with the `overridden` channels taken from the controllers.
+fn merge(
+    writer: &mut SBusWriter,
+    received: &SBusPacket,
+    controllers: Option<&[u16; 16]>,
+    overridden: &[usize],
+) -> [u8; FRAME_LEN] {
+    let mut channels = received.channels;
+    if let Some(controllers) = controllers {
+        for &channel in overridden {
+            channels[channel] = controllers[channel];
+        }
+    }
+
+    writer.encode(&channels, received.flags())
+}
+
+/// Passes the SBUS frames from a receiver on `input` through to `output`,
+/// with the `overridden` channels (0 based) replaced by the controllers'
+/// while one of them is driving. Frames only go out when the receiver sends
+/// one, so if it goes quiet the car's own failsafe takes over. Both ports
+/// speak `variant`.
+pub fn start_proxy(
+    input: &str,
+    output: &str,
+    variant: Variant,
+    overridden: Vec<usize>,
+    state_store: Arc<ArcSwap<StateManager>>,
+) -> Result<ProxyLink, String> {
+    let mut input_port =
+        open_sbus_port(input, variant).map_err(|e| format!("Failed to open {}: {}", input, e))?;
+    let mut output_port =
+        open_sbus_port(output, variant).map_err(|e| format!("Failed to open {}: {}", output, e))?;
+
+    let shared = Shared {
+        latest: Arc::new(Mutex::new(None)),
+        output_ok: Arc::new(AtomicBool::new(true)),
+    };
+    let proxy_shared = shared.clone();
+    let input = input.to_owned();
+
+    thread::spawn(move || {
+        let mut parser = SBusPacketParser::new();
+        let mut writer = SBusWriter::new(variant);
+        let mut overriding = false;
+        // No more than a frame at a time, see the monitor
+        let mut buf = [0u8; 25];
+
+        loop {
+            match input_port.read(&mut buf) {
+                Ok(len) => parser.push_bytes(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    error!("Reading {} failed, proxy stopped: {}", input, e);
+                    return;
+                }
+            }
+
+            while let Some(packet) = parser.try_parse() {
+                let driving = state_store.load().arbiter.owner().is_some();
+                let controllers = proxy_shared.overriding(driving, Instant::now());
+
+                if controllers.is_some() != overriding {
+                    overriding = controllers.is_some();
+                    if overriding {
+                        info!("Controllers overriding channels {:?}", overridden);
+                    } else {
+                        info!("Passing the receiver through");
+                    }
+                }
+
+                let frame = merge(&mut writer, &packet, controllers.as_ref(), &overridden);
+
+                let result = output_port.write_all(&frame);
+                if let Err(e) = &result {
+                    error!("Proxy write failed: {}", e);
+                }
+                proxy_shared
+                    .output_ok
+                    .store(result.is_ok(), Ordering::Relaxed);
+            }
+        }
+    });
+
+    Ok(ProxyLink { shared })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sbus_codec::decode;
+
+    fn link() -> ProxyLink {
+        ProxyLink {
+            shared: Shared {
+                latest: Arc::new(Mutex::new(None)),
+                output_ok: Arc::new(AtomicBool::new(true)),
+            },
+        }
+    }
+
+    fn received() -> SBusPacket {
+        SBusPacket {
+            channels: [1000; 16],
+            d1: false,
+            d2: false,
+            failsafe: false,
+            frame_lost: false,
+        }
+    }
+
+    fn merged(link: &ProxyLink, driving: bool, now: Instant) -> [u16; 16] {
+        let controllers = link.shared.overriding(driving, now);
+        let frame = merge(
+            &mut SBusWriter::new(Variant::Standard),
+            &received(),
+            controllers.as_ref(),
+            &[0, 1],
+        );
+        decode(&frame).unwrap().0
+    }
+
+    #[test]
+    fn controllers_override_their_channels() {
+        let mut link = link();
+        link.send_channels([1500; 16]).unwrap();
+
+        let channels = merged(&link, true, Instant::now());
+        assert_eq!(channels[..3], [1500, 1500, 1000]);
+    }
+
+    #[test]
+    fn receiver_comes_back_after_the_controllers_go_quiet() {
+        let mut link = link();
+        link.send_channels([1500; 16]).unwrap();
+        let quiet = Instant::now() + OVERRIDE_TIMEOUT;
+
+        // The car thread keeps repeating the last command at its cadence
+        link.repeat_channels([1500; 16]).unwrap();
+        link.repeat_channels([1500; 16]).unwrap();
+
+        assert_eq!(merged(&link, true, quiet), [1000; 16]);
+    }
+
+    #[test]
+    fn repeats_keep_the_channels_current() {
+        let mut link = link();
+        link.send_channels([1500; 16]).unwrap();
+        link.repeat_channels([1200; 16]).unwrap();
+
+        let channels = merged(&link, true, Instant::now());
+        assert_eq!(channels[..3], [1200, 1200, 1000]);
+    }
+
+    #[test]
+    fn nothing_overrides_with_nobody_driving() {
+        let mut link = link();
+        link.send_channels([1500; 16]).unwrap();
+
+        assert_eq!(merged(&link, false, Instant::now()), [1000; 16]);
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::controls::Bindings;
+use crate::feedback::{FeedbackEvent, PlayerLights};
+use crate::input::{Button, ControllerInput, InputError, InputSource};
+use crate::state_manager::Slot;
+
+/// One line of a recording. Times are milliseconds since recording started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    /// A reading from a controller, after tilt steering.
+    Input {
+        t: f64,
+        device: String,
+        /// Where the controller drove from.
+        slot: Slot,
+        /// The name of its button layout.
+        bindings: String,
+        horizontal: f32,
+        vertical: f32,
+        buttons: Vec<Button>,
+    },
+    /// The channels of an SBUS frame written to the car.
+    Frame {
+        t: f64,
+        channels: [u16; 16],
+        /// Why the write failed, if it did.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// Writes a session to a JSON-lines file, one `Entry` per line. Clones can be
+/// handed to every thread, the file is written from a thread of its own.
+#[derive(Clone)]
+pub struct Recorder {
+    tx: Sender<Entry>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Creates the file at `path`. The returned thread finishes once every
+    /// clone of the recorder has been dropped.
+    pub fn create(path: &str) -> io::Result<(Recorder, JoinHandle<()>)> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let (tx, rx) = mpsc::channel::<Entry>();
+
+        let handle = thread::spawn(move || {
+            while let Ok(entry) = rx.recv() {
+                // Flush whenever we catch up, so a crash loses as little as possible
+                let result = std::iter::once(entry)
+                    .chain(rx.try_iter())
+                    .try_for_each(|entry| {
+                        serde_json::to_writer(&mut file, &entry)?;
+                        file.write_all(b"\n")
+                    })
+                    .and_then(|_| file.flush());
+
+                if let Err(e) = result {
+                    error!("Recording stopped, writing failed: {}", e);
+                    return;
+                }
+            }
+        });
+
+        Ok((
+            Recorder {
+                tx,
+                start: Instant::now(),
+            },
+            handle,
+        ))
+    }
+
+    fn since_start(&self, time: Instant) -> f64 {
+        time.saturating_duration_since(self.start).as_secs_f64() * 1000.0
+    }
+
+    pub fn record_input(&self, input: &ControllerInput, slot: Slot, bindings: &Bindings) {
+        let mut buttons: Vec<Button> = input.buttons.iter().copied().collect();
+        buttons.sort_by_key(|&b| b as u8);
+
+        self.send(Entry::Input {
+            t: self.since_start(input.timestamp),
+            device: input.device.clone(),
+            slot,
+            bindings: bindings.name.to_owned(),
+            horizontal: input.horizontal,
+            vertical: input.vertical,
+            buttons,
+        });
+    }
+
+    pub fn record_frame(&self, channels: [u16; 16], result: &io::Result<()>) {
+        self.send(Entry::Frame {
+            t: self.since_start(Instant::now()),
+            channels,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+
+    // If the writer thread gave up there is nothing more to do, it logged why
+    fn send(&self, entry: Entry) {
+        let _ = self.tx.send(entry);
+    }
+}
+
+/// Wraps a controller so that every reading it gives is recorded.
+pub struct RecordingInput<S> {
+    inner: S,
+    slot: Slot,
+    recorder: Recorder,
+}
+
+impl<S: InputSource> RecordingInput<S> {
+    pub fn new(inner: S, slot: Slot, recorder: Recorder) -> RecordingInput<S> {
+        RecordingInput {
+            inner,
+            slot,
+            recorder,
+        }
+    }
+}
+
+impl<S: InputSource> InputSource for RecordingInput<S> {
+    fn read_input(&mut self) -> Result<ControllerInput, InputError> {
+        let input = self.inner.read_input()?;
+        self.recorder
+            .record_input(&input, self.slot, self.inner.bindings());
+        Ok(input)
+    }
+
+    fn bindings(&self) -> &'static Bindings {
+        self.inner.bindings()
+    }
+
+    fn feedback(&mut self, event: FeedbackEvent) {
+        self.inner.feedback(event)
+    }
+
+    fn indicate(&mut self, lights: PlayerLights) {
+        self.inner.indicate(lights)
+    }
+}
@@ -0,0 +1,902 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use crate::car::build_channels;
+use crate::link::Link;
+use crate::secure::{random_nonce, SecureChannel, Side, NONCE_LEN, TAG_LEN};
+use crate::utils::map_axis;
+
+// Every packet starts with this, anything else on the port is ignored
+const MAGIC: [u8; 4] = *b"GLRB";
+// The magic, the kind, the session and the counter, sent in the clear
+const HEADER_LEN: usize = 17;
+// A sequence number, a time and 16 channels
+const CHANNELS_LEN: usize = 44;
+const MAX_PACKET_LEN: usize = HEADER_LEN + CHANNELS_LEN + TAG_LEN;
+
+// The handshake, a hello from the controller side and the vehicle's welcome
+const HELLO: u8 = 0;
+const WELCOME: u8 = 1;
+// Sealed under the session's keys
+const CHANNELS: u8 = 2;
+const PING: u8 = 3;
+const PONG: u8 = 4;
+
+// How often the controller side says hello until the vehicle welcomes it
+const HELLO_INTERVAL: Duration = Duration::from_millis(250);
+// How often the vehicle checks the controller side is still answering
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+// The vehicle fails safe when no channels have arrived for this long
+const PACKET_TIMEOUT: Duration = Duration::from_millis(250);
+// The controller side starts a new session when the vehicle has been quiet
+// this long
+const VEHICLE_TIMEOUT: Duration = Duration::from_secs(1);
+// A hello that isn't followed by a sealed packet is forgotten after this
+// long, and only this many are kept at once
+const PENDING_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_PENDING: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    kind: u8,
+    /// Taken from the controller side's hello nonce.
+    session: u32,
+    /// The counter the rest of the packet is sealed under.
+    counter: u64,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = self.kind;
+        header[5..9].copy_from_slice(&self.session.to_be_bytes());
+        header[9..].copy_from_slice(&self.counter.to_be_bytes());
+        header
+    }
+
+    /// The header and what follows it.
+    fn decode(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[..4] != MAGIC {
+            return None;
+        }
+        let header = Header {
+            kind: packet[4],
+            session: u32::from_be_bytes(packet[5..9].try_into().unwrap()),
+            counter: u64::from_be_bytes(packet[9..HEADER_LEN].try_into().unwrap()),
+        };
+        Some((header, &packet[HEADER_LEN..]))
+    }
+}
+
+fn session_id(client_nonce: &[u8; NONCE_LEN]) -> u32 {
+    u32::from_be_bytes(client_nonce[..4].try_into().unwrap())
+}
+
+fn hello(client_nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+    let header = Header {
+        kind: HELLO,
+        session: session_id(client_nonce),
+        counter: 0,
+    };
+    [&header.encode()[..], client_nonce].concat()
+}
+
+/// What goes over the wire once a session is set up. Times are microseconds
+/// on the sender's own clock. A pong carries both sides' clocks, so the
+/// vehicle can tell how old the channels are when they arrive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Packet {
+    Channels {
+        seq: u32,
+        sent: u64,
+        channels: [u16; 16],
+    },
+    Ping {
+        sent: u64,
+    },
+    Pong {
+        /// The ping's time, on the vehicle's clock.
+        sent: u64,
+        /// When it was answered, on the controller side's clock.
+        clock: u64,
+    },
+}
+
+impl Packet {
+    fn encode(&self) -> (u8, Vec<u8>) {
+        match *self {
+            Packet::Channels {
+                seq,
+                sent,
+                channels,
+            } => {
+                let mut payload = Vec::with_capacity(CHANNELS_LEN);
+                payload.extend_from_slice(&seq.to_be_bytes());
+                payload.extend_from_slice(&sent.to_be_bytes());
+                for channel in channels {
+                    payload.extend_from_slice(&channel.to_be_bytes());
+                }
+                (CHANNELS, payload)
+            }
+            Packet::Ping { sent } => (PING, sent.to_be_bytes().to_vec()),
+            Packet::Pong { sent, clock } => {
+                (PONG, [sent.to_be_bytes(), clock.to_be_bytes()].concat())
+            }
+        }
+    }
+
+    fn decode(kind: u8, payload: &[u8]) -> Option<Packet> {
+        match (kind, payload.len()) {
+            (CHANNELS, CHANNELS_LEN) => {
+                let mut channels = [0; 16];
+                for (channel, bytes) in channels.iter_mut().zip(payload[12..].chunks(2)) {
+                    *channel = u16::from_be_bytes([bytes[0], bytes[1]]);
+                }
+                Some(Packet::Channels {
+                    seq: u32::from_be_bytes(payload[..4].try_into().unwrap()),
+                    sent: u64::from_be_bytes(payload[4..12].try_into().unwrap()),
+                    channels,
+                })
+            }
+            (PING, 8) => Some(Packet::Ping {
+                sent: u64::from_be_bytes(payload.try_into().unwrap()),
+            }),
+            (PONG, 16) => Some(Packet::Pong {
+                sent: u64::from_be_bytes(payload[..8].try_into().unwrap()),
+                clock: u64::from_be_bytes(payload[8..].try_into().unwrap()),
+            }),
+            _ => None,
+        }
+    }
+
+    fn seal(&self, session: u32, channel: &mut SecureChannel) -> Vec<u8> {
+        let (kind, payload) = self.encode();
+        let header = Header {
+            kind,
+            session,
+            counter: channel.next_counter(),
+        };
+        let encoded = header.encode();
+        [
+            &encoded[..],
+            &channel.seal(header.counter, &encoded, &payload),
+        ]
+        .concat()
+    }
+
+    /// The packet, unless it is forged, tampered with, replayed or older than
+    /// one already opened.
+    fn open(header: &Header, sealed: &[u8], channel: &mut SecureChannel) -> Option<Packet> {
+        let payload = channel.open(header.counter, &header.encode(), sealed)?;
+        Packet::decode(header.kind, &payload)
+    }
+}
+
+fn micros_since(start: Instant) -> u64 {
+    start.elapsed().as_micros() as u64
+}
+
+/// The controller side of a session, shared with the thread answering the
+/// vehicle.
+struct ControllerSession {
+    /// Sent in every hello until the vehicle welcomes it.
+    client_nonce: [u8; NONCE_LEN],
+    last_hello: Option<Instant>,
+    /// Set once the vehicle has proved it has the key.
+    channel: Option<SecureChannel>,
+    last_ping: Instant,
+}
+
+impl ControllerSession {
+    fn new() -> ControllerSession {
+        ControllerSession {
+            client_nonce: random_nonce(),
+            last_hello: None,
+            channel: None,
+            last_ping: Instant::now(),
+        }
+    }
+
+    /// Takes the vehicle's welcome, if it is for our hello and sealed with
+    /// the key.
+    fn welcome(&mut self, key: &[u8], header: &Header, body: &[u8]) {
+        if self.channel.is_some() || header.session != session_id(&self.client_nonce) {
+            return;
+        }
+        let Some((server_nonce, tag)) = body.split_first_chunk::<NONCE_LEN>() else {
+            return;
+        };
+
+        let mut channel =
+            SecureChannel::new(key, Side::Controller, &self.client_nonce, server_nonce);
+        let header = [&header.encode()[..], server_nonce].concat();
+        if channel.open(0, &header, tag).is_none() {
+            warn!("Ignoring a welcome that isn't sealed with our key");
+            return;
+        }
+        info!("Secure session with the vehicle set up");
+        self.channel = Some(channel);
+        self.last_ping = Instant::now();
+    }
+}
+
+/// Sends the controllers' frames to a vehicle over UDP instead of to a serial
+/// port, and answers the vehicle's pings. Everything but the handshake is
+/// encrypted and authenticated with keys derived from `key`.
+pub struct UdpLink {
+    socket: UdpSocket,
+    seq: u32,
+    start: Instant,
+    session: Arc<Mutex<ControllerSession>>,
+}
+
+impl UdpLink {
+    pub fn connect(address: &str, key: &str) -> io::Result<UdpLink> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+
+        let start = Instant::now();
+        let session = Arc::new(Mutex::new(ControllerSession::new()));
+        let answer = socket.try_clone()?;
+        let thread_session = session.clone();
+        let key = key.as_bytes().to_vec();
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            loop {
+                let len = match answer.recv(&mut buf) {
+                    Ok(len) => len,
+                    // Nothing listening on the vehicle side yet
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                        thread::sleep(PING_INTERVAL);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Listening to the vehicle failed: {}", e);
+                        return;
+                    }
+                };
+                let Some((header, body)) = Header::decode(&buf[..len]) else {
+                    continue;
+                };
+
+                let mut session = thread_session.lock().unwrap();
+                if header.kind == WELCOME {
+                    session.welcome(&key, &header, body);
+                    continue;
+                }
+                let id = session_id(&session.client_nonce);
+                let Some(channel) = session.channel.as_mut().filter(|_| header.session == id)
+                else {
+                    continue;
+                };
+                match Packet::open(&header, body, channel) {
+                    Some(Packet::Ping { sent }) => {
+                        let pong = Packet::Pong {
+                            sent,
+                            clock: micros_since(start),
+                        }
+                        .seal(id, channel);
+                        session.last_ping = Instant::now();
+                        let _ = answer.send(&pong);
+                    }
+                    Some(_) => {}
+                    None => debug!("Dropped a forged, tampered or replayed packet"),
+                }
+            }
+        });
+
+        info!("Sending to the vehicle at {}", address);
+        Ok(UdpLink {
+            socket,
+            seq: 0,
+            start,
+            session,
+        })
+    }
+}
+
+impl Link for UdpLink {
+    fn send_channels(&mut self, channels: [u16; 16]) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        if session.channel.is_some() && session.last_ping.elapsed() > VEHICLE_TIMEOUT {
+            warn!("The vehicle stopped answering, starting a new session");
+            *session = ControllerSession::new();
+        }
+
+        let id = session_id(&session.client_nonce);
+        let Some(channel) = session.channel.as_mut() else {
+            if session
+                .last_hello
+                .is_none_or(|last| last.elapsed() >= HELLO_INTERVAL)
+            {
+                self.socket.send(&hello(&session.client_nonce))?;
+                session.last_hello = Some(Instant::now());
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no session with the vehicle yet",
+            ));
+        };
+
+        self.seq = self.seq.wrapping_add(1);
+        let packet = Packet::Channels {
+            seq: self.seq,
+            sent: micros_since(self.start),
+            channels,
+        };
+        self.socket.send(&packet.seal(id, channel))?;
+        Ok(())
+    }
+}
+
+/// A hello the vehicle has answered, which becomes the controller side once
+/// a packet sealed with its keys arrives.
+struct Pending {
+    address: SocketAddr,
+    client_nonce: [u8; NONCE_LEN],
+    channel: SecureChannel,
+    /// Sent again if the hello is repeated, so both sides end up with the
+    /// same keys.
+    welcome: Vec<u8>,
+    created: Instant,
+}
+
+impl Pending {
+    fn new(key: &[u8], address: SocketAddr, client_nonce: [u8; NONCE_LEN]) -> Pending {
+        let server_nonce = random_nonce();
+        let mut channel = SecureChannel::new(key, Side::Vehicle, &client_nonce, &server_nonce);
+        let header = Header {
+            kind: WELCOME,
+            session: session_id(&client_nonce),
+            counter: channel.next_counter(),
+        };
+        let sealed = [&header.encode()[..], &server_nonce].concat();
+        let tag = channel.seal(header.counter, &sealed, &[]);
+
+        Pending {
+            address,
+            client_nonce,
+            channel,
+            welcome: [sealed, tag].concat(),
+            created: Instant::now(),
+        }
+    }
+}
+
+/// What the vehicle knows about the controller side.
+struct Controller {
+    address: SocketAddr,
+    session: u32,
+    channel: SecureChannel,
+    seq: u32,
+    channels: [u16; 16],
+    received: Instant,
+    /// Round trip time of the last ping answered.
+    round_trip: Option<Duration>,
+    last_pong: Option<Instant>,
+    /// The controller side's clock less ours, in microseconds, as of the
+    /// last pong.
+    clock_offset: Option<i64>,
+    /// How old the last channels were, while they arrive too late to use.
+    late: Option<Duration>,
+    lost: u64,
+}
+
+impl Controller {
+    fn new(pending: Pending) -> Controller {
+        let centre = map_axis(0.0, false);
+        Controller {
+            address: pending.address,
+            session: session_id(&pending.client_nonce),
+            channel: pending.channel,
+            seq: 0,
+            channels: build_channels(centre, centre, true, false),
+            received: Instant::now(),
+            round_trip: None,
+            last_pong: None,
+            clock_offset: None,
+            late: None,
+            lost: 0,
+        }
+    }
+
+    /// Takes in a packet from the controller side. Channels older than
+    /// `max_latency`, or of unknown age, aren't used. They could drive the car
+    /// from a stick that has long since moved.
+    fn receive(&mut self, packet: Packet, start: Instant, max_latency: Duration) {
+        let now = micros_since(start);
+        match packet {
+            Packet::Channels {
+                seq,
+                sent,
+                channels,
+            } => {
+                self.lost += u64::from(seq.wrapping_sub(self.seq).wrapping_sub(1));
+                self.seq = seq;
+
+                let age = self.clock_offset.map(|offset| {
+                    let age = now as i64 - (sent as i64 - offset);
+                    Duration::from_micros(age.max(0) as u64)
+                });
+                match age {
+                    Some(age) if age <= max_latency => {
+                        self.late = None;
+                        self.channels = channels;
+                        self.received = Instant::now();
+                    }
+                    Some(age) => self.late = Some(age),
+                    // Until a pong lines the clocks up there is no telling
+                    None => {}
+                }
+            }
+            Packet::Pong { sent, clock } => {
+                let round_trip = now.saturating_sub(sent);
+                debug!(
+                    "Round trip {:?}, {} packets lost",
+                    Duration::from_micros(round_trip),
+                    self.lost
+                );
+                // Assuming the pong took half the round trip to come back
+                self.clock_offset = Some(clock as i64 - (sent + round_trip / 2) as i64);
+                self.round_trip = Some(Duration::from_micros(round_trip));
+                self.last_pong = Some(Instant::now());
+            }
+            Packet::Ping { .. } => {}
+        }
+    }
+
+    /// Why the car shouldn't be driven from this controller right now, if
+    /// it shouldn't.
+    fn failsafe_reason(&self, max_latency: Duration) -> Option<String> {
+        if let Some(age) = self.late {
+            return Some(format!("channels arriving {:?} late", age));
+        }
+        if self.received.elapsed() > PACKET_TIMEOUT {
+            return Some(format!("no channels for {:?}", PACKET_TIMEOUT));
+        }
+        match (self.round_trip, self.last_pong) {
+            (Some(round_trip), _) if round_trip > max_latency => {
+                Some(format!("round trip of {:?}", round_trip))
+            }
+            (_, Some(last_pong)) if last_pong.elapsed() > PING_INTERVAL + max_latency => {
+                Some("pings unanswered".to_owned())
+            }
+            (_, None) => Some("no ping answered yet".to_owned()),
+            _ => None,
+        }
+    }
+}
+
+/// Runs the vehicle side: takes the frames a `UdpLink` with the same `key`
+/// sends to `address` and passes them to `link`, one every `frame_interval`.
+/// The car gets stopped, disarmed frames instead while the controller side is
+/// quiet, or its round trip time or the age of its channels is over
+/// `max_latency`. Packets that aren't
+/// sealed with the session's keys, or that have been seen before, are dropped.
+pub fn run_vehicle(
+    address: &str,
+    key: &str,
+    link: Box<dyn Link>,
+    frame_interval: Duration,
+    max_latency: Duration,
+) {
+    let socket = match UdpSocket::bind(address) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Vehicle listening on {}", address);
+    serve(socket, key.as_bytes(), link, frame_interval, max_latency);
+}
+
+/// Runs the vehicle side on a socket that is already bound.
+fn serve(
+    socket: UdpSocket,
+    key: &[u8],
+    mut link: Box<dyn Link>,
+    frame_interval: Duration,
+    max_latency: Duration,
+) {
+    let start = Instant::now();
+    let centre = map_axis(0.0, false);
+    let stopped = build_channels(centre, centre, true, false);
+
+    let mut controller: Option<Controller> = None;
+    // Hellos are kept apart by where they came from, so nobody else can
+    // knock a handshake out by saying hello too
+    let mut pending: HashMap<SocketAddr, Pending> = HashMap::new();
+    let mut failsafe: Option<String> = Some("no controller yet".to_owned());
+    let mut next_frame = Instant::now();
+    let mut next_ping = Instant::now();
+    let mut buf = [0u8; MAX_PACKET_LEN];
+
+    loop {
+        let wait = next_frame
+            .min(next_ping)
+            .saturating_duration_since(Instant::now());
+        // A zero timeout is an error, rather than not waiting
+        let _ = socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))));
+
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => match Header::decode(&buf[..len]) {
+                Some((header, body)) if header.kind == HELLO => {
+                    let Ok(client_nonce) = <[u8; NONCE_LEN]>::try_from(body) else {
+                        continue;
+                    };
+                    // The current controller keeps driving until the new
+                    // one proves it has the key
+                    pending.retain(|_, p| p.created.elapsed() < PENDING_TIMEOUT);
+                    let repeated = pending
+                        .get(&from)
+                        .is_some_and(|p| p.client_nonce == client_nonce);
+                    if !repeated {
+                        if pending.len() >= MAX_PENDING && !pending.contains_key(&from) {
+                            debug!("Too many hellos, ignoring {}", from);
+                            continue;
+                        }
+                        debug!("Hello from {}", from);
+                        pending.insert(from, Pending::new(key, from, client_nonce));
+                    }
+                    let _ = socket.send_to(&pending[&from].welcome, from);
+                }
+                Some((header, body)) => {
+                    let current = controller
+                        .as_mut()
+                        .filter(|c| c.address == from && c.session == header.session);
+                    if let Some(c) = current {
+                        match Packet::open(&header, body, &mut c.channel) {
+                            Some(packet) => c.receive(packet, start, max_latency),
+                            None => debug!("Dropped a forged, tampered or replayed packet"),
+                        }
+                    } else if let Some(p) = pending
+                        .get_mut(&from)
+                        .filter(|p| session_id(&p.client_nonce) == header.session)
+                    {
+                        match Packet::open(&header, body, &mut p.channel) {
+                            Some(packet) => {
+                                info!("Controller side connected from {}", from);
+                                let mut c = Controller::new(pending.remove(&from).unwrap());
+                                c.receive(packet, start, max_latency);
+                                controller = Some(c);
+                            }
+                            None => debug!("Dropped a forged, tampered or replayed packet"),
+                        }
+                    }
+                }
+                None => {}
+            },
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => warn!("Receiving from the controller side failed: {}", e),
+        }
+
+        let now = Instant::now();
+        if now >= next_ping {
+            if let Some(c) = &mut controller {
+                let ping = Packet::Ping {
+                    sent: micros_since(start),
+                };
+                let _ = socket.send_to(&ping.seal(c.session, &mut c.channel), c.address);
+            }
+            next_ping = (next_ping + PING_INTERVAL).max(now);
+        }
+
+        if now >= next_frame {
+            let reason = match &controller {
+                Some(c) => c.failsafe_reason(max_latency),
+                None => Some("no controller yet".to_owned()),
+            };
+            if reason != failsafe {
+                match &reason {
+                    Some(reason) if failsafe.is_none() => warn!("Failsafe: {}", reason),
+                    Some(_) => {}
+                    None => info!("Driving from the controller side"),
+                }
+                failsafe = reason;
+            }
+
+            let channels = match (&controller, &failsafe) {
+                (Some(c), None) => c.channels,
+                _ => stopped,
+            };
+            if let Err(e) = link.send_channels(channels) {
+                error!("Sending frame failed: {}", e);
+            }
+            next_frame = (next_frame + frame_interval).max(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    const KEY: &str = "correct horse battery staple";
+
+    fn address() -> SocketAddr {
+        "127.0.0.1:9000".parse().unwrap()
+    }
+
+    /// Runs the handshake, the controller side with `controller_key` and the
+    /// vehicle with `vehicle_key`.
+    fn handshake(controller_key: &[u8], vehicle_key: &[u8]) -> (ControllerSession, Pending) {
+        let mut session = ControllerSession::new();
+        let hello = hello(&session.client_nonce);
+        let (header, body) = Header::decode(&hello).unwrap();
+        assert_eq!(header.kind, HELLO);
+
+        let pending = Pending::new(vehicle_key, address(), body.try_into().unwrap());
+        let (header, body) = Header::decode(&pending.welcome).unwrap();
+        session.welcome(controller_key, &header, body);
+        (session, pending)
+    }
+
+    #[test]
+    fn handshake_sets_up_matching_keys() {
+        let (mut session, mut pending) = handshake(KEY.as_bytes(), KEY.as_bytes());
+        let id = session_id(&session.client_nonce);
+        let channel = session.channel.as_mut().expect("welcome accepted");
+
+        let packet = Packet::Channels {
+            seq: 1,
+            sent: 42,
+            channels: [1024; 16],
+        };
+        let sealed = packet.seal(id, channel);
+        let (header, body) = Header::decode(&sealed).unwrap();
+        assert_eq!(
+            Packet::open(&header, body, &mut pending.channel),
+            Some(packet)
+        );
+    }
+
+    #[test]
+    fn handshake_with_another_key_fails() {
+        let (session, _) = handshake(KEY.as_bytes(), b"correct horse battery stapler");
+        assert!(session.channel.is_none());
+    }
+
+    #[test]
+    fn welcome_for_another_hello_is_ignored() {
+        let mut session = ControllerSession::new();
+        let pending = Pending::new(KEY.as_bytes(), address(), random_nonce());
+        let (header, body) = Header::decode(&pending.welcome).unwrap();
+        session.welcome(KEY.as_bytes(), &header, body);
+        assert!(session.channel.is_none());
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let packets = [
+            Packet::Channels {
+                seq: u32::MAX,
+                sent: u64::MAX,
+                channels: std::array::from_fn(|i| i as u16 * 100),
+            },
+            Packet::Ping { sent: 7 },
+            Packet::Pong { sent: 9, clock: 11 },
+        ];
+        for packet in packets {
+            let (kind, payload) = packet.encode();
+            assert_eq!(Packet::decode(kind, &payload), Some(packet));
+            assert_eq!(Packet::decode(kind, &payload[1..]), None);
+        }
+    }
+
+    const DRIVING: [u16; 16] = [1500; 16];
+
+    /// Hands the vehicle's frames to the test.
+    struct FrameLink(Sender<[u16; 16]>);
+
+    impl Link for FrameLink {
+        fn send_channels(&mut self, channels: [u16; 16]) -> io::Result<()> {
+            let _ = self.0.send(channels);
+            Ok(())
+        }
+    }
+
+    /// A vehicle on a free loopback port, and the frames it sends the car.
+    fn vehicle(max_latency: Duration) -> (SocketAddr, Receiver<[u16; 16]>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let (frames, received) = mpsc::channel();
+        thread::spawn(move || {
+            let link = Box::new(FrameLink(frames));
+            serve(
+                socket,
+                KEY.as_bytes(),
+                link,
+                Duration::from_millis(5),
+                max_latency,
+            )
+        });
+        (address, received)
+    }
+
+    /// Passes packets between the controller side and the `vehicle`, holding
+    /// the controller side's of the kinds `delays` picks back for `delay`.
+    /// Those stay in order, as on a slow network, rather than newer ones
+    /// overtaking.
+    fn slow_relay(vehicle: SocketAddr, delay: Duration, delays: fn(u8) -> bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let (queue, queued) = mpsc::channel::<(Instant, Vec<u8>)>();
+
+        let forward = socket.try_clone().unwrap();
+        thread::spawn(move || {
+            for (due, packet) in queued {
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+                let _ = forward.send_to(&packet, vehicle);
+            }
+        });
+        thread::spawn(move || {
+            let mut controller = None;
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+                if from == vehicle {
+                    if let Some(controller) = controller {
+                        let _ = socket.send_to(&buf[..len], controller);
+                    }
+                } else {
+                    controller = Some(from);
+                    match Header::decode(&buf[..len]) {
+                        Some((header, _)) if delays(header.kind) => {
+                            let _ = queue.send((Instant::now() + delay, buf[..len].to_vec()));
+                        }
+                        _ => {
+                            let _ = socket.send_to(&buf[..len], vehicle);
+                        }
+                    }
+                }
+            }
+        });
+        address
+    }
+
+    /// Keeps sending `DRIVING` through `link`, until the car gets it or
+    /// `timeout` runs out. Whether it did.
+    fn drives(link: &mut UdpLink, frames: &Receiver<[u16; 16]>, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let _ = link.send_channels(DRIVING);
+            thread::sleep(Duration::from_millis(5));
+            if frames.try_iter().any(|channels| channels == DRIVING) {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn frames_arrive_then_fail_safe_when_they_stop() {
+        let (address, frames) = vehicle(Duration::from_millis(100));
+        let mut link = UdpLink::connect(&address.to_string(), KEY).unwrap();
+        assert!(drives(&mut link, &frames, Duration::from_secs(2)));
+
+        // The controller side still answers pings, only the channels stop
+        // after this last one
+        let stopped_at = Instant::now();
+        link.send_channels(DRIVING).unwrap();
+        let centre = map_axis(0.0, false);
+        let stopped = build_channels(centre, centre, true, false);
+        let failsafe = frames
+            .iter()
+            .take_while(|_| stopped_at.elapsed() < Duration::from_secs(2))
+            .find(|&channels| channels != DRIVING);
+        assert_eq!(failsafe, Some(stopped));
+        assert!(stopped_at.elapsed() >= PACKET_TIMEOUT);
+    }
+
+    #[test]
+    fn fails_safe_while_the_round_trip_is_too_long() {
+        let delay = Duration::from_millis(50);
+
+        let (address, frames) = vehicle(Duration::from_millis(20));
+        let relay = slow_relay(address, delay, |_| true);
+        let mut link = UdpLink::connect(&relay.to_string(), KEY).unwrap();
+        assert!(!drives(&mut link, &frames, Duration::from_secs(1)));
+
+        // The same relay is fine with a more lenient vehicle
+        let (address, frames) = vehicle(Duration::from_millis(200));
+        let relay = slow_relay(address, delay, |_| true);
+        let mut link = UdpLink::connect(&relay.to_string(), KEY).unwrap();
+        assert!(drives(&mut link, &frames, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn fails_safe_while_the_channels_arrive_late() {
+        // Pings and pongs go straight through, only the channels queue up
+        let delay = Duration::from_millis(50);
+        let late = |kind| kind == CHANNELS;
+
+        let (address, frames) = vehicle(Duration::from_millis(20));
+        let relay = slow_relay(address, delay, late);
+        let mut link = UdpLink::connect(&relay.to_string(), KEY).unwrap();
+        assert!(!drives(&mut link, &frames, Duration::from_secs(1)));
+
+        let (address, frames) = vehicle(Duration::from_millis(200));
+        let relay = slow_relay(address, delay, late);
+        let mut link = UdpLink::connect(&relay.to_string(), KEY).unwrap();
+        assert!(drives(&mut link, &frames, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn channels_older_than_the_max_latency_are_not_used() {
+        let max_latency = Duration::from_millis(20);
+        let (_, pending) = handshake(KEY.as_bytes(), KEY.as_bytes());
+        let mut controller = Controller::new(pending);
+        let start = Instant::now();
+        let channels = |seq, age: u64| {
+            // The controller side's clock is well ahead of ours
+            let clock = micros_since(start) + 5_000_000;
+            Packet::Channels {
+                seq,
+                sent: clock - age,
+                channels: [seq as u16; 16],
+            }
+        };
+
+        // Until a pong lines the clocks up, there is no telling how old
+        // channels are
+        let centre = controller.channels;
+        controller.receive(channels(1, 0), start, max_latency);
+        assert_eq!(controller.channels, centre);
+        controller.receive(
+            Packet::Pong {
+                sent: micros_since(start),
+                clock: micros_since(start) + 5_000_000,
+            },
+            start,
+            max_latency,
+        );
+
+        controller.receive(channels(2, 1_000), start, max_latency);
+        assert_eq!(controller.channels, [2; 16]);
+        assert_eq!(controller.failsafe_reason(max_latency), None);
+
+        controller.receive(channels(3, 50_000), start, max_latency);
+        assert_eq!(controller.channels, [2; 16]);
+        assert!(controller
+            .failsafe_reason(max_latency)
+            .is_some_and(|reason| reason.contains("late")));
+
+        controller.receive(channels(4, 1_000), start, max_latency);
+        assert_eq!(controller.channels, [4; 16]);
+        assert_eq!(controller.failsafe_reason(max_latency), None);
+    }
+
+    #[test]
+    fn hellos_from_elsewhere_do_not_break_a_handshake() {
+        let (address, frames) = vehicle(Duration::from_millis(100));
+        let spammer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(3);
+            while Instant::now() < deadline {
+                let _ = spammer.send_to(&hello(&random_nonce()), address);
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let mut link = UdpLink::connect(&address.to_string(), KEY).unwrap();
+        assert!(drives(&mut link, &frames, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn wrong_key_never_drives() {
+        let (address, frames) = vehicle(Duration::from_millis(100));
+        let mut link = UdpLink::connect(&address.to_string(), "another key, long enough").unwrap();
+        assert!(!drives(&mut link, &frames, Duration::from_millis(500)));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::{mpsc::Sender, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use log::{error, info};
+
+use crate::car::CarCommand;
+use crate::controls::{self, bindings_named, Bindings};
+use crate::input::{ControllerInput, InputError, InputSource};
+use crate::recorder::{Entry, Recorder, RecordingInput};
+use crate::state_manager::{Slot, StateManager};
+use crate::tui::{Dashboard, DashboardInput};
+use crate::utils::map_axis;
+
+/// One recorded reading, due `at` after the replay started.
+struct Reading {
+    at: Duration,
+    input: ControllerInput,
+}
+
+/// Plays back one controller's readings from a recording, with the gaps
+/// between them as they were recorded, divided by the speed factor.
+pub struct ReplayInput {
+    readings: std::vec::IntoIter<Reading>,
+    bindings: &'static Bindings,
+    start: Instant,
+}
+
+impl InputSource for ReplayInput {
+    fn read_input(&mut self) -> Result<ControllerInput, InputError> {
+        let Reading { at, mut input } = self.readings.next().ok_or(InputError::Closed)?;
+
+        thread::sleep((self.start + at).saturating_duration_since(Instant::now()));
+        input.timestamp = Instant::now();
+
+        Ok(input)
+    }
+
+    fn bindings(&self) -> &'static Bindings {
+        self.bindings
+    }
+}
+
+/// Reads the recording at `path` into one source per recorded controller,
+/// with the slot it drove from.
+fn load(path: &str, speed: f64) -> Result<Vec<(Slot, ReplayInput)>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let start = Instant::now();
+
+    let mut devices: HashMap<String, (Slot, &'static Bindings, Vec<Reading>)> = HashMap::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| format!("{} line {}: {}", path, number + 1, e))?;
+
+        // Frames are what came out last time, they get worked out afresh
+        if let Entry::Input {
+            t,
+            device,
+            slot,
+            bindings,
+            horizontal,
+            vertical,
+            buttons,
+        } = entry
+        {
+            let bindings = bindings_named(&bindings).ok_or_else(|| {
+                format!(
+                    "{} line {}: unknown bindings {}",
+                    path,
+                    number + 1,
+                    bindings
+                )
+            })?;
+            let reading = Reading {
+                at: Duration::from_secs_f64(t / 1000.0 / speed),
+                input: ControllerInput {
+                    device: device.clone(),
+                    timestamp: start,
+                    horizontal,
+                    vertical,
+                    buttons: buttons.into_iter().collect(),
+                    motion: Vec::new(),
+                },
+            };
+
+            devices
+                .entry(device)
+                .or_insert_with(|| (slot, bindings, Vec::new()))
+                .2
+                .push(reading);
+        }
+    }
+
+    Ok(devices
+        .into_values()
+        .map(|(slot, bindings, readings)| {
+            (
+                slot,
+                ReplayInput {
+                    readings: readings.into_iter(),
+                    bindings,
+                    start,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Drives the car from the recording at `path` through the same pipeline as
+/// live controllers, `speed` times as fast as it was recorded. Returns once
+/// every controller in it has finished, leaving the car disarmed.
+pub fn run_replay(
+    path: &str,
+    speed: f64,
+    state_store: Arc<ArcSwap<StateManager>>,
+    car_tx: Sender<CarCommand>,
+    recorder: Option<Recorder>,
+    dashboard: Option<Dashboard>,
+) {
+    let sources = match load(path, speed) {
+        Ok(sources) => sources,
+        Err(e) => {
+            error!("Replay failed: {}", e);
+            return;
+        }
+    };
+    info!("Replaying {} controllers from {}", sources.len(), path);
+
+    let handles: Vec<_> = sources
+        .into_iter()
+        .map(|(slot, source)| {
+            let mut source: Box<dyn InputSource> = Box::new(source);
+            if let Some(recorder) = &recorder {
+                source = Box::new(RecordingInput::new(source, slot, recorder.clone()));
+            }
+            if let Some(dashboard) = &dashboard {
+                source = Box::new(DashboardInput::new(source, slot, dashboard.clone()));
+            }
+
+            let state_store = state_store.clone();
+            let car_tx = car_tx.clone();
+            thread::spawn(move || controls::run_input_source(source, slot, state_store, car_tx))
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Whatever the recording ended on, don't leave the car driving
+    let centre = map_axis(0.0, false);
+    car_tx
+        .send(CarCommand::SendData(centre, centre, true, false, None))
+        .unwrap();
+    info!("Replay finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controls::LEFT_JOYCON;
+    use crate::input::Button;
+    use std::{env, fs};
+
+    #[test]
+    fn plays_a_recording_back_at_speed() {
+        let path = env::temp_dir().join(format!("glorb-replay-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let (recorder, writer) = Recorder::create(path).unwrap();
+        let start = Instant::now();
+        let reading = |ms, vertical, buttons: &[Button]| ControllerInput {
+            device: "left".to_owned(),
+            timestamp: start + Duration::from_millis(ms),
+            horizontal: -0.5,
+            vertical,
+            buttons: buttons.iter().copied().collect(),
+            motion: Vec::new(),
+        };
+        recorder.record_input(&reading(100, 0.25, &[Button::Up]), Slot::Left, &LEFT_JOYCON);
+        recorder.record_frame([1000; 16], &Ok(()));
+        recorder.record_input(&reading(300, -0.75, &[]), Slot::Left, &LEFT_JOYCON);
+        drop(recorder);
+        writer.join().unwrap();
+
+        // The frame is recorded, but worked out afresh rather than replayed
+        let recording = fs::read_to_string(path).unwrap();
+        let entries: Vec<Entry> = recording
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        match &entries[1] {
+            Entry::Frame {
+                channels, error, ..
+            } => assert_eq!((channels, error), (&[1000; 16], &None)),
+            other => panic!("expected a frame, got {:?}", other),
+        }
+
+        let loaded = Instant::now();
+        let mut sources = load(path, 2.0).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(sources.len(), 1);
+        let (slot, mut source) = sources.pop().unwrap();
+        assert_eq!(slot, Slot::Left);
+        assert_eq!(source.bindings().name, LEFT_JOYCON.name);
+
+        // Twice as fast, so at half the recorded times
+        for (due, vertical, buttons) in [(50, 0.25, vec![Button::Up]), (150, -0.75, vec![])] {
+            let input = source.read_input().unwrap();
+            let at = input.timestamp.duration_since(loaded);
+            assert!(
+                at >= Duration::from_millis(due) && at < Duration::from_millis(due + 30),
+                "due at {}ms, came at {:?}",
+                due,
+                at
+            );
+            assert_eq!(input.device, "left");
+            assert_eq!(input.horizontal, -0.5);
+            assert_eq!(input.vertical, vertical);
+            assert_eq!(input.buttons, buttons.into_iter().collect());
+        }
+        assert!(matches!(source.read_input(), Err(InputError::Closed)));
+    }
+}
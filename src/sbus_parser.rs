@@ -1,121 +1,290 @@
-use arraydeque::{ArrayDeque, Wrapping};
-
-// The flag by should start with 4 0s
-const SBUS_FLAG_BYTE_MASK: u8 = 0b11110000;
-const SBUS_HEADER_BYTE: u8 = 0x0F;
-const SBUS_FOOTER_BYTE: u8 = 0b00000000;
+use std::time::{Duration, Instant};
 
-const SBUS_PACKET_SIZE: usize = 25;
+use arraydeque::{ArrayDeque, Wrapping};
+use sbus_codec::{Flags, FRAME_LEN, HEADER};
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
 pub struct SBusPacket {
-    channels: [u16; 16],
-    d1: bool,
-    d2: bool,
-    failsafe: bool,
-    frame_lost: bool,
+    pub channels: [u16; 16],
+    /// Digital channels 17 and 18.
+    pub d1: bool,
+    pub d2: bool,
+    pub failsafe: bool,
+    pub frame_lost: bool,
+}
+
+impl SBusPacket {
+    pub fn flags(&self) -> Flags {
+        Flags {
+            d1: self.d1,
+            d2: self.d2,
+            frame_lost: self.frame_lost,
+            failsafe: self.failsafe,
+        }
+    }
+}
+
+/// How the line has been doing since the parser was created.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParserStats {
+    pub frames: u64,
+    /// Bytes thrown away while looking for a frame, or because they were
+    /// pushed faster than they were parsed.
+    pub bytes_discarded: u64,
+    /// Times the parser lost sync after a good frame and had to look for a
+    /// header again.
+    pub resyncs: u64,
+    /// Frames with the frame lost flag set.
+    pub frame_lost: u64,
+    /// Frames with the failsafe flag set.
+    pub failsafe: u64,
+    pub last_frame: Option<Instant>,
+    /// Time between the last two frames.
+    pub last_interval: Option<Duration>,
+    pub min_interval: Option<Duration>,
+    pub max_interval: Option<Duration>,
+    total_interval: Duration,
+}
+
+impl ParserStats {
+    /// Average time between frames.
+    pub fn mean_interval(&self) -> Option<Duration> {
+        (self.frames > 1).then(|| self.total_interval.div_f64((self.frames - 1) as f64))
+    }
+
+    fn record_frame(&mut self, packet: &SBusPacket, now: Instant) {
+        self.frames += 1;
+        self.frame_lost += u64::from(packet.frame_lost);
+        self.failsafe += u64::from(packet.failsafe);
+
+        if let Some(last) = self.last_frame {
+            let interval = now.saturating_duration_since(last);
+            self.last_interval = Some(interval);
+            self.min_interval = Some(self.min_interval.map_or(interval, |min| min.min(interval)));
+            self.max_interval = Some(self.max_interval.map_or(interval, |max| max.max(interval)));
+            self.total_interval += interval;
+        }
+        self.last_frame = Some(now);
+    }
 }
 
 pub struct SBusPacketParser {
-    buffer: ArrayDeque<[u8; (SBUS_PACKET_SIZE * 2) as usize], Wrapping>,
+    buffer: ArrayDeque<[u8; FRAME_LEN * 2], Wrapping>,
+    stats: ParserStats,
+    // Whether the last thing parsed was a good frame, so losing sync only
+    // counts once
+    synced: bool,
 }
 
 impl SBusPacketParser {
     pub fn new() -> SBusPacketParser {
         SBusPacketParser {
             buffer: ArrayDeque::new(),
+            stats: ParserStats::default(),
+            synced: false,
         }
     }
 
+    pub fn stats(&self) -> &ParserStats {
+        &self.stats
+    }
+
     pub fn push_bytes(&mut self, bytes: &[u8]) {
         bytes.iter().for_each(|b| {
-            self.buffer.push_back(*b);
+            // A full buffer drops its oldest byte
+            if self.buffer.push_back(*b).is_some() {
+                self.stats.bytes_discarded += 1;
+            }
         })
     }
 
+    fn lose_sync(&mut self) {
+        if self.synced {
+            self.stats.resyncs += 1;
+            self.synced = false;
+        }
+    }
+
+    /// Pops bytes until the buffer starts with a header, counting them.
+    fn skip_to_header(&mut self) {
+        while !self.buffer.is_empty() && *self.buffer.front().unwrap() != HEADER {
+            self.buffer.pop_front();
+            self.stats.bytes_discarded += 1;
+        }
+    }
+
     pub fn try_parse(&mut self) -> Option<SBusPacket> {
+        self.try_parse_at(Instant::now())
+    }
+
+    /// Like `try_parse`, with `now` as the time a frame arrived.
+    fn try_parse_at(&mut self, now: Instant) -> Option<SBusPacket> {
         // We can't have a packet if we don't have enough bytes
-        if self.buffer.len() < SBUS_PACKET_SIZE {
+        if self.buffer.len() < FRAME_LEN {
             return None;
         }
 
         // If the first byte is not a header byte,
-        if *self.buffer.get(0).unwrap() != SBUS_HEADER_BYTE {
-            while self.buffer.len() > 0 && *self.buffer.get(0).unwrap() != SBUS_HEADER_BYTE {
-                let _ = self.buffer.pop_front().unwrap();
-                //println!("Popped byte: {:#?}", popped);
-            }
+        if *self.buffer.front().unwrap() != HEADER {
+            self.lose_sync();
+            self.skip_to_header();
 
             return None;
-        } else if *self.buffer.get(SBUS_PACKET_SIZE - 1).unwrap() == SBUS_FOOTER_BYTE
-            && self.buffer.get(SBUS_PACKET_SIZE - 2).unwrap() & SBUS_FLAG_BYTE_MASK == 0
-        {
-            // This seems like a valid packet!
-            // Start popping the bytes
-
-            let mut data_bytes: [u16; 23] = [0; 23];
-            for i in 0..23 {
-                data_bytes[i] = self.buffer.pop_front().unwrap_or(0) as u16;
-            }
+        }
 
-            let mut channels: [u16; 16] = [0; 16];
-
-            channels[0] = (((data_bytes[1]) | (data_bytes[2] << 8)) as u16 & 0x07FF).into();
-            channels[1] = ((((data_bytes[2] >> 3) | (data_bytes[3] << 5)) as u16) & 0x07FF).into();
-            channels[2] = ((((data_bytes[3] >> 6) | (data_bytes[4] << 2) | (data_bytes[5] << 10))
-                as u16)
-                & 0x07FF)
-                .into();
-            channels[3] = ((((data_bytes[5] >> 1) | (data_bytes[6] << 7)) as u16) & 0x07FF).into();
-            channels[4] = ((((data_bytes[6] >> 4) | (data_bytes[7] << 4)) as u16) & 0x07FF).into();
-            channels[5] = ((((data_bytes[7] >> 7) | (data_bytes[8] << 1) | (data_bytes[9] << 9))
-                as u16)
-                & 0x07FF)
-                .into();
-            channels[6] = ((((data_bytes[9] >> 2) | (data_bytes[10] << 6)) as u16) & 0x07FF).into();
-            channels[7] =
-                ((((data_bytes[10] >> 5) | (data_bytes[11] << 3)) as u16) & 0x07FF).into();
-            channels[8] = ((((data_bytes[12]) | (data_bytes[13] << 8)) as u16) & 0x07FF).into();
-            channels[9] =
-                ((((data_bytes[13] >> 3) | (data_bytes[14] << 5)) as u16) & 0x07FF).into();
-            channels[10] =
-                ((((data_bytes[14] >> 6) | (data_bytes[15] << 2) | (data_bytes[16] << 10)) as u16)
-                    & 0x07FF)
-                    .into();
-            channels[11] =
-                ((((data_bytes[16] >> 1) | (data_bytes[17] << 7)) as u16) & 0x07FF).into();
-            channels[12] =
-                ((((data_bytes[17] >> 4) | (data_bytes[18] << 4)) as u16) & 0x07FF).into();
-            channels[13] =
-                ((((data_bytes[18] >> 7) | (data_bytes[19] << 1) | (data_bytes[20] << 9)) as u16)
-                    & 0x07FF)
-                    .into();
-            channels[14] =
-                ((((data_bytes[20] >> 2) | (data_bytes[21] << 6)) as u16) & 0x07FF).into();
-            channels[15] =
-                ((((data_bytes[21] >> 5) | (data_bytes[22] << 3)) as u16) & 0x07FF).into();
-
-            let flag_byte = self.buffer.pop_front().unwrap_or(0);
-
-            return Some(SBusPacket {
-                channels,
-                d1: is_flag_set(flag_byte, 0),
-                d2: is_flag_set(flag_byte, 1),
-                frame_lost: is_flag_set(flag_byte, 2),
-                failsafe: is_flag_set(flag_byte, 3),
-            });
-        } else {
-            // We had a header byte, but this doesnt appear to be a valid frame, we are probably out of sync
-            // Pop until we find a header again
-            while self.buffer.len() > 0 && *self.buffer.get(0).unwrap() != SBUS_HEADER_BYTE {
+        let mut frame = [0u8; FRAME_LEN];
+        for (i, byte) in frame.iter_mut().enumerate() {
+            *byte = *self.buffer.get(i).unwrap();
+        }
+
+        match sbus_codec::decode(&frame) {
+            Some((channels, flags)) => {
+                // This seems like a valid packet! Pop it, footer and all, so
+                // the next frame starts on its header
+                self.buffer.drain(..FRAME_LEN);
+
+                let packet = SBusPacket {
+                    channels,
+                    d1: flags.d1,
+                    d2: flags.d2,
+                    frame_lost: flags.frame_lost,
+                    failsafe: flags.failsafe,
+                };
+                self.stats.record_frame(&packet, now);
+                self.synced = true;
+
+                Some(packet)
+            }
+            None => {
+                // We had a header byte, but this doesnt appear to be a valid frame, we are probably out of sync
+                // Drop the header that wasn't one and pop until we find a header again
                 self.buffer.pop_front();
+                self.stats.bytes_discarded += 1;
+                self.lose_sync();
+                self.skip_to_header();
+
+                None
             }
         }
-
-        return None;
     }
 }
 
-fn is_flag_set(flag_byte: u8, idx: u8) -> bool {
-    flag_byte & 1 << idx == 1
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sbus_codec::{encode_into, FOOTER};
+
+    fn frame(flags: Flags) -> [u8; FRAME_LEN] {
+        let mut frame = [0; FRAME_LEN];
+        encode_into(&[1000; 16], flags, FOOTER, &mut frame);
+        frame
+    }
+
+    /// Pushes `bytes` and parses everything they complete, at `now`.
+    fn feed(parser: &mut SBusPacketParser, bytes: &[u8], now: Instant) -> Vec<SBusPacket> {
+        parser.push_bytes(bytes);
+        (0..bytes.len())
+            .filter_map(|_| parser.try_parse_at(now))
+            .collect()
+    }
+
+    #[test]
+    fn garbage_is_discarded_and_losing_sync_counts_once() {
+        let mut parser = SBusPacketParser::new();
+        let good = frame(Flags::default());
+        let now = Instant::now();
+
+        // Garbage before the first frame isn't a loss of sync
+        assert_eq!(feed(&mut parser, &[0xFF, 0xFE, 0xFD], now).len(), 0);
+        assert_eq!(feed(&mut parser, &good, now).len(), 1);
+        assert_eq!(parser.stats().bytes_discarded, 3);
+        assert_eq!(parser.stats().resyncs, 0);
+
+        // A run of garbage after a frame is one resync, however long
+        assert_eq!(feed(&mut parser, &[0xFF; 5], now).len(), 0);
+        assert_eq!(feed(&mut parser, &good, now).len(), 1);
+        assert_eq!(parser.stats().bytes_discarded, 8);
+        assert_eq!(parser.stats().resyncs, 1);
+        assert_eq!(parser.stats().frames, 2);
+    }
+
+    #[test]
+    fn a_dropped_byte_costs_one_frame_and_one_resync() {
+        let mut parser = SBusPacketParser::new();
+        let good = frame(Flags::default());
+        let mut short = good.to_vec();
+        short.remove(10);
+        let now = Instant::now();
+
+        assert_eq!(feed(&mut parser, &good, now).len(), 1);
+        assert_eq!(feed(&mut parser, &short, now).len(), 0);
+        // The next frame completes the short one's window, which fails to
+        // decode, and is then found again
+        assert_eq!(feed(&mut parser, &good, now).len(), 1);
+        assert_eq!(feed(&mut parser, &good, now).len(), 1);
+
+        let stats = parser.stats();
+        assert_eq!(stats.frames, 3);
+        assert_eq!(stats.bytes_discarded, short.len() as u64);
+        assert_eq!(stats.resyncs, 1);
+    }
+
+    #[test]
+    fn counts_flags_and_times_frames() {
+        let mut parser = SBusPacketParser::new();
+        let start = Instant::now();
+        let lost = Flags {
+            frame_lost: true,
+            ..Flags::default()
+        };
+        let failsafe = Flags {
+            frame_lost: true,
+            failsafe: true,
+            ..Flags::default()
+        };
+
+        for (ms, flags) in [
+            (0, Flags::default()),
+            (7, lost),
+            (21, failsafe),
+            (28, Flags::default()),
+        ] {
+            let now = start + Duration::from_millis(ms);
+            assert_eq!(feed(&mut parser, &frame(flags), now).len(), 1);
+        }
+
+        let stats = parser.stats();
+        assert_eq!(stats.frames, 4);
+        assert_eq!(stats.frame_lost, 2);
+        assert_eq!(stats.failsafe, 1);
+        assert_eq!(stats.last_frame, Some(start + Duration::from_millis(28)));
+        assert_eq!(stats.last_interval, Some(Duration::from_millis(7)));
+        assert_eq!(stats.min_interval, Some(Duration::from_millis(7)));
+        assert_eq!(stats.max_interval, Some(Duration::from_millis(14)));
+        assert_eq!(
+            stats.mean_interval(),
+            Some(Duration::from_millis(28).div_f64(3.0))
+        );
+    }
+
+    #[test]
+    fn bytes_pushed_faster_than_parsed_are_discarded() {
+        let mut parser = SBusPacketParser::new();
+        let good = frame(Flags::default());
+        for _ in 0..3 {
+            parser.push_bytes(&good);
+        }
+        assert_eq!(parser.stats().bytes_discarded, FRAME_LEN as u64);
+        assert!(parser.try_parse().is_some());
+        assert!(parser.try_parse().is_some());
+        assert!(parser.try_parse().is_none());
+    }
+
+    #[test]
+    fn no_interval_from_a_single_frame() {
+        let mut parser = SBusPacketParser::new();
+        feed(&mut parser, &frame(Flags::default()), Instant::now());
+        assert_eq!(parser.stats().last_interval, None);
+        assert_eq!(parser.stats().mean_interval(), None);
+    }
 }
@@ -1,34 +1,76 @@
-// const SBUS_HEADER_BYTE: u8 = 0x0F;
-// const SBUS_FOOTER_BYTE: u8 = 0b00000000;
-// const SBUS_PACKET_SIZE: usize = 25;
-
-pub fn encode_sbus(channels: [u16; 16]) -> [u8; 25] {
-    let mut buf = [0u8; 25];
-    buf[0] = 0x0F;
-    buf[1] = (channels[0] & 0x07FF) as u8;
-    buf[2] = (((channels[0] & 0x07FF) >> 8) | ((channels[1] & 0x07FF) << 3)) as u8;
-    buf[3] = (((channels[1] & 0x07FF) >> 5) | ((channels[2] & 0x07FF) << 6)) as u8;
-    buf[4] = ((channels[2] & 0x07FF) >> 2) as u8;
-    buf[5] = (((channels[2] & 0x07FF) >> 10) | ((channels[3] & 0x07FF) << 1)) as u8;
-    buf[6] = (((channels[3] & 0x07FF) >> 7) | ((channels[4] & 0x07FF) << 4)) as u8;
-    buf[7] = (((channels[4] & 0x07FF) >> 4) | ((channels[5] & 0x07FF) << 7)) as u8;
-    buf[8] = ((channels[5] & 0x07FF) >> 1) as u8;
-    buf[9] = (((channels[5] & 0x07FF) >> 9) | ((channels[6] & 0x07FF) << 2)) as u8;
-    buf[10] = (((channels[6] & 0x07FF) >> 6) | ((channels[7] & 0x07FF) << 5)) as u8;
-    buf[11] = ((channels[7] & 0x07FF) >> 3) as u8;
-    buf[12] = (channels[8] & 0x07FF) as u8;
-    buf[13] = (((channels[8] & 0x07FF) >> 8) | ((channels[9] & 0x07FF) << 3)) as u8;
-    buf[14] = (((channels[9] & 0x07FF) >> 5) | ((channels[10] & 0x07FF) << 6)) as u8;
-    buf[15] = ((channels[10] & 0x07FF) >> 2) as u8;
-    buf[16] = (((channels[10] & 0x07FF) >> 10) | ((channels[11] & 0x07FF) << 1)) as u8;
-    buf[17] = (((channels[11] & 0x07FF) >> 7) | ((channels[12] & 0x07FF) << 4)) as u8;
-    buf[18] = (((channels[12] & 0x07FF) >> 4) | ((channels[13] & 0x07FF) << 7)) as u8;
-    buf[19] = ((channels[13] & 0x07FF) >> 1) as u8;
-    buf[20] = (((channels[13] & 0x07FF) >> 9) | ((channels[14] & 0x07FF) << 2)) as u8;
-    buf[21] = (((channels[14] & 0x07FF) >> 6) | ((channels[15] & 0x07FF) << 5)) as u8;
-    buf[22] = ((channels[15] & 0x07FF) >> 3) as u8;
-    buf[23] = 0x00;
-    buf[24] = 0x00;
-
-    buf
+use sbus_codec::{Flags, Variant, FRAME_LEN};
+
+/// Encodes a stream of frames for one variant, keeping track of where SBUS2
+/// is in its footer cycle.
+pub struct SBusWriter {
+    variant: Variant,
+    frames: usize,
+}
+
+impl SBusWriter {
+    pub fn new(variant: Variant) -> SBusWriter {
+        SBusWriter { variant, frames: 0 }
+    }
+
+    /// The next frame to send.
+    pub fn encode(&mut self, channels: &[u16; 16], flags: Flags) -> [u8; FRAME_LEN] {
+        let mut frame = [0; FRAME_LEN];
+        let footer = self.variant.footer(self.frames);
+        sbus_codec::encode_into(channels, flags, footer, &mut frame);
+        self.frames = self.frames.wrapping_add(1);
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sbus_codec::{decode, FOOTER, SBUS2_FOOTERS};
+
+    const ALL: [Variant; 4] = [
+        Variant::Standard,
+        Variant::HighSpeed,
+        Variant::Fast,
+        Variant::Sbus2,
+    ];
+
+    fn footers(variant: Variant, frames: usize) -> Vec<u8> {
+        let mut writer = SBusWriter::new(variant);
+        (0..frames)
+            .map(|_| writer.encode(&[1024; 16], Flags::default())[FRAME_LEN - 1])
+            .collect()
+    }
+
+    #[test]
+    fn sbus2_cycles_through_its_footers() {
+        let expected: Vec<u8> = SBUS2_FOOTERS.iter().cycle().take(10).copied().collect();
+        assert_eq!(footers(Variant::Sbus2, 10), expected);
+    }
+
+    #[test]
+    fn other_variants_keep_the_plain_footer() {
+        for variant in [Variant::Standard, Variant::HighSpeed, Variant::Fast] {
+            assert_eq!(footers(variant, 5), [FOOTER; 5], "{:?}", variant);
+        }
+    }
+
+    #[test]
+    fn every_variant_decodes_its_own_frames() {
+        let channels: [u16; 16] = std::array::from_fn(|i| i as u16 * 127);
+        let flags = Flags {
+            failsafe: true,
+            ..Flags::default()
+        };
+        for variant in ALL {
+            let mut writer = SBusWriter::new(variant);
+            for _ in 0..SBUS2_FOOTERS.len() {
+                assert_eq!(
+                    decode(&writer.encode(&channels, flags)),
+                    Some((channels, flags)),
+                    "{:?}",
+                    variant
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,217 @@
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+pub const NONCE_LEN: usize = 16;
+/// What sealing adds to a payload.
+pub const TAG_LEN: usize = 16;
+/// Shortest pre-shared key accepted. It is all that stands between the car
+/// and anyone who captures a handshake and tries keys offline.
+pub const MIN_KEY_LEN: usize = 16;
+
+const CONTROLLER_INFO: &[u8] = b"glorb-control controller to vehicle";
+const VEHICLE_INFO: &[u8] = b"glorb-control vehicle to controller";
+
+/// A fresh random handshake nonce.
+pub fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Which end of the link we are, each sends with its own key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Controller,
+    Vehicle,
+}
+
+/// One session's keys and counters. Every packet is sealed under a counter
+/// that only goes up, so a packet that is replayed, or arrives after a newer
+/// one, doesn't open.
+pub struct SecureChannel {
+    sender: ChaCha20Poly1305,
+    receiver: ChaCha20Poly1305,
+    next_counter: u64,
+    last_received: Option<u64>,
+}
+
+impl SecureChannel {
+    /// Derives the session's keys from the pre-shared `key` and both sides'
+    /// nonces, so no two sessions share keys even with the same `key`.
+    pub fn new(
+        key: &[u8],
+        side: Side,
+        client_nonce: &[u8; NONCE_LEN],
+        server_nonce: &[u8; NONCE_LEN],
+    ) -> SecureChannel {
+        let salt = [&client_nonce[..], &server_nonce[..]].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), key);
+        let derive = |info| {
+            let mut okm = [0; 32];
+            hkdf.expand(info, &mut okm)
+                .expect("32 bytes is a valid HKDF-SHA256 length");
+            ChaCha20Poly1305::new(&okm.into())
+        };
+
+        let (send_info, receive_info) = match side {
+            Side::Controller => (CONTROLLER_INFO, VEHICLE_INFO),
+            Side::Vehicle => (VEHICLE_INFO, CONTROLLER_INFO),
+        };
+        SecureChannel {
+            sender: derive(send_info),
+            receiver: derive(receive_info),
+            next_counter: 0,
+            last_received: None,
+        }
+    }
+
+    /// The counter to seal the next packet under.
+    pub fn next_counter(&mut self) -> u64 {
+        let counter = self.next_counter;
+        self.next_counter += 1;
+        counter
+    }
+
+    /// Encrypts `payload` and authenticates it along with `header`, which is
+    /// sent in the clear.
+    pub fn seal(&self, counter: u64, header: &[u8], payload: &[u8]) -> Vec<u8> {
+        self.sender
+            .encrypt(
+                &nonce(counter),
+                Payload {
+                    msg: payload,
+                    aad: header,
+                },
+            )
+            .expect("ChaCha20-Poly1305 takes payloads this size")
+    }
+
+    /// The payload, if `sealed` was sealed by the other side under `counter`
+    /// with this `header`, and nothing newer has been opened already.
+    pub fn open(&mut self, counter: u64, header: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if self.last_received.is_some_and(|last| counter <= last) {
+            return None;
+        }
+        let payload = self
+            .receiver
+            .decrypt(
+                &nonce(counter),
+                Payload {
+                    msg: sealed,
+                    aad: header,
+                },
+            )
+            .ok()?;
+        self.last_received = Some(counter);
+        Some(payload)
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"correct horse battery staple";
+    const HEADER: &[u8] = b"header";
+
+    fn pair(controller_key: &[u8], vehicle_key: &[u8]) -> (SecureChannel, SecureChannel) {
+        let client_nonce = random_nonce();
+        let server_nonce = random_nonce();
+        (
+            SecureChannel::new(
+                controller_key,
+                Side::Controller,
+                &client_nonce,
+                &server_nonce,
+            ),
+            SecureChannel::new(vehicle_key, Side::Vehicle, &client_nonce, &server_nonce),
+        )
+    }
+
+    #[test]
+    fn opens_what_the_other_side_sealed() {
+        let (mut controller, mut vehicle) = pair(KEY, KEY);
+        for payload in [&b"first"[..], b"second"] {
+            let counter = controller.next_counter();
+            let sealed = controller.seal(counter, HEADER, payload);
+            assert_eq!(sealed.len(), payload.len() + TAG_LEN);
+            assert_eq!(
+                vehicle.open(counter, HEADER, &sealed).as_deref(),
+                Some(payload)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_flipped_bit() {
+        let (mut controller, mut vehicle) = pair(KEY, KEY);
+        let counter = controller.next_counter();
+        let sealed = controller.seal(counter, HEADER, b"channels");
+
+        for bit in 0..sealed.len() * 8 {
+            let mut tampered = sealed.clone();
+            tampered[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(
+                vehicle.open(counter, HEADER, &tampered),
+                None,
+                "bit {}",
+                bit
+            );
+        }
+        assert_eq!(vehicle.open(counter, b"Header", &sealed), None);
+        assert!(vehicle.open(counter, HEADER, &sealed).is_some());
+    }
+
+    #[test]
+    fn rejects_a_replay() {
+        let (mut controller, mut vehicle) = pair(KEY, KEY);
+        let counter = controller.next_counter();
+        let sealed = controller.seal(counter, HEADER, b"channels");
+
+        assert!(vehicle.open(counter, HEADER, &sealed).is_some());
+        assert_eq!(vehicle.open(counter, HEADER, &sealed), None);
+    }
+
+    #[test]
+    fn rejects_an_older_counter() {
+        let (mut controller, mut vehicle) = pair(KEY, KEY);
+        let older = controller.next_counter();
+        let older_sealed = controller.seal(older, HEADER, b"older");
+        let newer = controller.next_counter();
+        let newer_sealed = controller.seal(newer, HEADER, b"newer");
+
+        assert!(vehicle.open(newer, HEADER, &newer_sealed).is_some());
+        assert_eq!(vehicle.open(older, HEADER, &older_sealed), None);
+    }
+
+    #[test]
+    fn rejects_its_own_direction() {
+        let (mut controller, mut vehicle) = pair(KEY, KEY);
+        let counter = controller.next_counter();
+        let sealed = controller.seal(counter, HEADER, b"channels");
+        // Reflected back at the controller side, as if the vehicle sent it
+        assert_eq!(controller.open(counter, HEADER, &sealed), None);
+
+        let counter = vehicle.next_counter();
+        let sealed = vehicle.seal(counter, HEADER, b"ping");
+        assert_eq!(vehicle.open(counter, HEADER, &sealed), None);
+        assert!(controller.open(counter, HEADER, &sealed).is_some());
+    }
+
+    #[test]
+    fn rejects_a_different_key() {
+        let (mut controller, mut vehicle) = pair(KEY, b"correct horse battery stapler");
+        let counter = controller.next_counter();
+        let sealed = controller.seal(counter, HEADER, b"channels");
+        assert_eq!(vehicle.open(counter, HEADER, &sealed), None);
+    }
+}
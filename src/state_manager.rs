@@ -1,40 +1,263 @@
-use joycon_rs::prelude::*;
+use std::time::Instant;
 
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::arbitration::{Arbiter, Policy};
+use crate::battery::BatteryGuard;
+use crate::drive_mode::{DriveMode, ModeEvent};
+use crate::feedback::FeedbackEvent;
 use crate::joycons::JoyConState;
+use crate::profiles::Profile;
+use crate::telemetry::{CrsfFrame, Telemetry};
+
+/// One of the two controller positions the car can be driven from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Slot {
+    Left,
+    Right,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::Left => Slot::Right,
+            Slot::Right => Slot::Left,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct StateManager {
     pub l: JoyConState,
     pub r: JoyConState,
+    /// Whether the last frame made it out to the car.
+    pub link_ok: bool,
+    /// Decides which of the armed controllers drives.
+    pub arbiter: Arbiter,
+    /// Scales the student's throttle in trainer mode, 1.0 leaves it alone.
+    pub student_throttle: f32,
+    /// Limits applied to everything sent to the car.
+    pub profile: Profile,
+    /// The latest the car has told us about itself.
+    pub telemetry: Telemetry,
+    /// Watches the battery voltage in the telemetry.
+    pub battery: BatteryGuard,
 }
 
 impl StateManager {
-    pub fn new() -> StateManager {
+    pub fn new(policy: Policy) -> StateManager {
         StateManager {
-            l: JoyConState {
-                forward: true,
-                armed: false,
-            },
-            r: JoyConState {
-                forward: true,
-                armed: false,
-            },
-        }
-    }
-
-    pub fn set_state(&mut self, joycon: JoyConDeviceType, forward: bool, armed: bool) {
-        match joycon {
-            JoyConDeviceType::JoyConL => {
-                self.l.forward = forward;
-                self.l.armed = armed;
+            l: JoyConState::default(),
+            r: JoyConState::default(),
+            link_ok: true,
+            arbiter: Arbiter::new(policy),
+            student_throttle: 1.0,
+            profile: Profile::default(),
+            telemetry: Telemetry::default(),
+            battery: BatteryGuard::default(),
+        }
+    }
+
+    pub fn slot(&self, slot: Slot) -> &JoyConState {
+        match slot {
+            Slot::Left => &self.l,
+            Slot::Right => &self.r,
+        }
+    }
+
+    pub fn slot_mut(&mut self, slot: Slot) -> &mut JoyConState {
+        match slot {
+            Slot::Left => &mut self.l,
+            Slot::Right => &mut self.r,
+        }
+    }
+
+    /// Whether the controller in `slot` is the one driving the car.
+    pub fn in_control(&self, slot: Slot) -> bool {
+        self.arbiter.owner() == Some(slot)
+    }
+
+    /// The mode the car is driven in, if anybody is driving: the driver's own,
+    /// except that an instructor who takes over without arming drives in the
+    /// student's, so the car doesn't disarm or turn round under them. Once the
+    /// instructor arms, their own mode counts.
+    pub fn driving_mode(&self) -> Option<DriveMode> {
+        let owner = self.arbiter.owner()?;
+        let mode = self.slot(owner).mode;
+        match self.arbiter.student() {
+            Some(student) if student != owner && !mode.is_armed() => Some(self.slot(student).mode),
+            _ => Some(mode),
+        }
+    }
+
+    /// Moves the controller in `slot` on to its next mode, logging the change
+    /// or why it was refused. An emergency stop stops both controllers.
+    pub fn transition(&mut self, slot: Slot, event: ModeEvent, throttle: f32) {
+        let slots = if event == ModeEvent::EStop {
+            vec![slot, slot.other()]
+        } else {
+            vec![slot]
+        };
+
+        for slot in slots {
+            let mode = self.slot(slot).mode;
+            match mode.next(event, throttle) {
+                Ok(next) if next != mode => {
+                    info!("{:?} controller: {:?} -> {:?}", slot, mode, next);
+                    self.slot_mut(slot).mode = next;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("{:?} controller: {}", slot, e),
             }
-            JoyConDeviceType::JoyConR => {
-                self.r.forward = forward;
-                self.r.armed = armed;
+        }
+    }
+
+    /// Lets the arbiter catch up after a controller armed or disarmed, and
+    /// logs any change of driver.
+    pub fn arbitrate(&mut self) {
+        let owner = self.arbiter.owner();
+        let (l, r) = (&self.l, &self.r);
+        let controller = |slot| match slot {
+            Slot::Left => l,
+            Slot::Right => r,
+        };
+        self.arbiter.update(
+            |slot| controller(slot).mode.is_armed(),
+            |slot| controller(slot).takeover,
+        );
+
+        if self.arbiter.owner() != owner {
+            match self.arbiter.owner() {
+                Some(slot) => info!("{:?} controller has control", slot),
+                None => info!("Nobody has control"),
             }
-            _ => {
-                println!("Unknown JoyCon type {:?}", joycon)
+        }
+    }
+
+    /// Takes in a telemetry frame from the car, disarming any armed
+    /// controller once the battery has been critical for too long.
+    pub fn update_telemetry(&mut self, frame: CrsfFrame, now: Instant) {
+        self.telemetry.apply(frame, now);
+        if let CrsfFrame::Battery { voltage, .. } = frame {
+            self.battery.update(voltage, now);
+        }
+
+        if self.battery.should_disarm(now) && (self.l.mode.is_armed() || self.r.mode.is_armed()) {
+            warn!("Battery critical for too long, disarming");
+            // Disarming would also take a controller out of its e-stop
+            for slot in [Slot::Left, Slot::Right] {
+                if self.slot(slot).mode.is_armed() {
+                    self.transition(slot, ModeEvent::Disarm, 0.0);
+                }
             }
+            self.arbitrate();
+        }
+    }
+
+    /// What changed for the controller in `slot` since `previous`, as
+    /// feedback for whoever is holding it.
+    pub fn events_since(&self, previous: &StateManager, slot: Slot) -> Vec<FeedbackEvent> {
+        let (before, after) = (previous.slot(slot), self.slot(slot));
+        let mut events = Vec::new();
+
+        if after.mode == DriveMode::EStop && before.mode != DriveMode::EStop {
+            events.push(FeedbackEvent::EStop);
+        } else if after.mode.is_armed() && !before.mode.is_armed() {
+            events.push(FeedbackEvent::Armed);
+        } else if before.mode.is_armed() && !after.mode.is_armed() {
+            events.push(FeedbackEvent::Disarmed);
+        }
+
+        if after.mode.is_forward() != before.mode.is_forward() {
+            events.push(FeedbackEvent::DirectionChanged);
         }
+
+        // Let the driver know someone is waiting to take over
+        if self.in_control(slot)
+            && self.arbiter.pending_request() == Some(slot.other())
+            && previous.arbiter.pending_request() != Some(slot.other())
+        {
+            events.push(FeedbackEvent::HandoverRequested);
+        }
+
+        if self.battery.level() > previous.battery.level() {
+            events.push(FeedbackEvent::LowBattery);
+        }
+
+        if previous.link_ok && !self.link_ok {
+            events.push(FeedbackEvent::Failsafe);
+        }
+
+        events
+    }
+}
+
+/// Updates the shared state. Several threads write to it, so `update` is
+/// retried on a fresh copy if another thread got there first.
+pub fn update_shared(
+    state_store: &ArcSwap<StateManager>,
+    mut update: impl FnMut(&mut StateManager),
+) {
+    state_store.rcu(|state_arc| {
+        let mut state = (**state_arc).clone();
+        update(&mut state);
+        state
+    });
+}
+
+/// Records whether the car is getting our frames, putting both controllers
+/// into failsafe while it isn't. The shared state is only written when that
+/// changes, not for every frame.
+pub fn set_link_ok(state_store: &ArcSwap<StateManager>, link_ok: bool) {
+    if state_store.load().link_ok != link_ok {
+        let event = if link_ok {
+            ModeEvent::LinkRestored
+        } else {
+            ModeEvent::LinkLost
+        };
+        update_shared(state_store, |state| {
+            state.link_ok = link_ok;
+            state.transition(Slot::Left, event, 0.0);
+            state.transition(Slot::Right, event, 0.0);
+            state.arbitrate();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryThresholds;
+    use std::time::Duration;
+
+    fn battery(voltage: f32) -> CrsfFrame {
+        CrsfFrame::Battery {
+            voltage,
+            current: 0.0,
+            remaining: 0,
+        }
+    }
+
+    #[test]
+    fn flat_battery_disarms_but_keeps_the_e_stop() {
+        let mut state = StateManager::new(Policy::default());
+        state.battery = BatteryGuard::new(Some(BatteryThresholds {
+            warning: 14.0,
+            critical: 13.2,
+        }));
+        state.l.mode = DriveMode::Driving;
+        state.r.mode = DriveMode::EStop;
+        state.arbitrate();
+
+        let start = Instant::now();
+        for seconds in 0..30 {
+            state.update_telemetry(battery(12.0), start + Duration::from_secs(seconds));
+        }
+
+        assert_eq!(state.l.mode, DriveMode::Disarmed);
+        assert_eq!(state.r.mode, DriveMode::EStop);
+        assert_eq!(state.arbiter.owner(), None);
     }
 }
@@ -0,0 +1,376 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use arraydeque::{ArrayDeque, Wrapping};
+use log::{debug, error, info};
+
+use crate::state_manager::{update_shared, StateManager};
+
+// CRSF frames are an address, a length, then that many bytes: the type, the
+// payload and a CRC of both
+const MAX_FRAME_LEN: usize = 64;
+const MIN_LENGTH: usize = 2;
+const ADDRESSES: [u8; 3] = [0xC8, 0xEA, 0xEE];
+
+const BATTERY_SENSOR: u8 = 0x08;
+const LINK_STATISTICS: u8 = 0x14;
+
+/// CRSF's CRC-8, polynomial 0xD5.
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0xD5
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// The telemetry frames we understand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrsfFrame {
+    Battery {
+        /// Volts.
+        voltage: f32,
+        /// Amps.
+        current: f32,
+        /// Per cent left, as the car reckons it.
+        remaining: u8,
+    },
+    LinkStatistics {
+        /// dBm at the car's receiver, on whichever antenna it is using.
+        rssi: i16,
+        /// Per cent of packets getting through to the car.
+        link_quality: u8,
+    },
+}
+
+impl CrsfFrame {
+    fn decode(kind: u8, payload: &[u8]) -> Option<CrsfFrame> {
+        match (kind, payload) {
+            (BATTERY_SENSOR, [v1, v2, c1, c2, _, _, _, remaining, ..]) => {
+                Some(CrsfFrame::Battery {
+                    voltage: f32::from(u16::from_be_bytes([*v1, *v2])) / 10.0,
+                    current: f32::from(u16::from_be_bytes([*c1, *c2])) / 10.0,
+                    remaining: *remaining,
+                })
+            }
+            (LINK_STATISTICS, [rssi1, rssi2, link_quality, _, antenna, ..]) => {
+                let rssi = if *antenna == 0 { rssi1 } else { rssi2 };
+                Some(CrsfFrame::LinkStatistics {
+                    rssi: -i16::from(*rssi),
+                    link_quality: *link_quality,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Pulls CRSF frames out of a byte stream, skipping anything it can't make
+/// sense of.
+pub struct CrsfParser {
+    buffer: ArrayDeque<[u8; MAX_FRAME_LEN * 2], Wrapping>,
+}
+
+impl CrsfParser {
+    pub fn new() -> CrsfParser {
+        CrsfParser {
+            buffer: ArrayDeque::new(),
+        }
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|b| {
+            self.buffer.push_back(*b);
+        })
+    }
+
+    /// The next frame we understand, if a whole one has arrived. Frames of
+    /// other types are skipped.
+    pub fn try_parse(&mut self) -> Option<CrsfFrame> {
+        loop {
+            // Look for an address
+            while self
+                .buffer
+                .front()
+                .is_some_and(|byte| !ADDRESSES.contains(byte))
+            {
+                self.buffer.pop_front();
+            }
+
+            let length = usize::from(*self.buffer.get(1)?);
+            if !(MIN_LENGTH..=MAX_FRAME_LEN - 2).contains(&length) {
+                // Not an address after all
+                self.buffer.pop_front();
+                continue;
+            }
+            if self.buffer.len() < length + 2 {
+                return None;
+            }
+
+            let body: Vec<u8> = self.buffer.iter().skip(2).take(length).copied().collect();
+            let (crc, body) = body.split_last().unwrap();
+            if crc8(body) != *crc {
+                self.buffer.pop_front();
+                continue;
+            }
+
+            self.buffer.drain(..length + 2);
+            if let Some(frame) = CrsfFrame::decode(body[0], &body[1..]) {
+                return Some(frame);
+            }
+        }
+    }
+}
+
+/// The latest telemetry from the car. Each value is missing until the car
+/// first sends it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Telemetry {
+    /// Battery voltage, volts.
+    pub voltage: Option<f32>,
+    /// Current draw, amps.
+    pub current: Option<f32>,
+    /// Per cent of the battery left.
+    pub remaining: Option<u8>,
+    /// Signal strength at the car's receiver, dBm.
+    pub rssi: Option<i16>,
+    /// Per cent of packets getting through to the car.
+    pub link_quality: Option<u8>,
+    /// When the last frame arrived.
+    pub updated: Option<Instant>,
+}
+
+impl Telemetry {
+    pub fn apply(&mut self, frame: CrsfFrame, now: Instant) {
+        match frame {
+            CrsfFrame::Battery {
+                voltage,
+                current,
+                remaining,
+            } => {
+                self.voltage = Some(voltage);
+                self.current = Some(current);
+                self.remaining = Some(remaining);
+            }
+            CrsfFrame::LinkStatistics { rssi, link_quality } => {
+                self.rssi = Some(rssi);
+                self.link_quality = Some(link_quality);
+            }
+        }
+        self.updated = Some(now);
+    }
+}
+
+impl fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(voltage) = self.voltage {
+            parts.push(format!("{:.1}V", voltage));
+        }
+        if let Some(current) = self.current {
+            parts.push(format!("{:.1}A", current));
+        }
+        if let Some(rssi) = self.rssi {
+            parts.push(format!("{}dBm", rssi));
+        }
+        if let Some(link_quality) = self.link_quality {
+            parts.push(format!("LQ {}%", link_quality));
+        }
+
+        if parts.is_empty() {
+            write!(f, "no telemetry")
+        } else {
+            write!(f, "{}", parts.join(" "))
+        }
+    }
+}
+
+/// Opens a serial port with the CRSF settings: 420000 baud, 8N1.
+pub fn open_telemetry_port(port_name: &str) -> serialport::Result<Box<dyn Read + Send>> {
+    let port = serialport::new(port_name, 420_000)
+        .timeout(Duration::from_millis(100))
+        .open()?;
+    Ok(Box::new(port))
+}
+
+/// Reads telemetry from `reader` into the shared state until it fails.
+pub fn start_telemetry(
+    mut reader: Box<dyn Read + Send>,
+    name: String,
+    state_store: Arc<ArcSwap<StateManager>>,
+) {
+    thread::spawn(move || {
+        let mut parser = CrsfParser::new();
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let mut receiving = false;
+
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => {
+                    info!("Telemetry from {} ended", name);
+                    return;
+                }
+                Ok(len) => parser.push_bytes(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    error!("Reading telemetry from {} failed: {}", name, e);
+                    return;
+                }
+            }
+
+            while let Some(frame) = parser.try_parse() {
+                if !receiving {
+                    info!("Receiving telemetry from {}", name);
+                    receiving = true;
+                }
+                debug!("Telemetry: {:?}", frame);
+
+                let now = Instant::now();
+                update_shared(&state_store, |state| state.update_telemetry(frame, now));
+            }
+        }
+    });
+}
+
+fn encode(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut body = vec![kind];
+    body.extend_from_slice(payload);
+    let crc = crc8(&body);
+
+    let mut frame = vec![ADDRESSES[0], (body.len() + 1) as u8];
+    frame.extend(body);
+    frame.push(crc);
+    frame
+}
+
+/// Stands in for the car's telemetry: a healthy 4S battery and a strong link,
+/// a few frames a second.
+pub struct MockTelemetry {
+    frames: Vec<Vec<u8>>,
+    next: usize,
+}
+
+impl MockTelemetry {
+    pub fn new() -> MockTelemetry {
+        let frames = [(162, 12, 95, 48), (161, 25, 94, 62), (159, 31, 94, 71)]
+            .into_iter()
+            .flat_map(|(voltage, current, remaining, rssi): (u16, u16, u8, u8)| {
+                let [v1, v2] = voltage.to_be_bytes();
+                let [c1, c2] = current.to_be_bytes();
+                [
+                    encode(BATTERY_SENSOR, &[v1, v2, c1, c2, 0, 0, 0, remaining]),
+                    encode(
+                        LINK_STATISTICS,
+                        &[rssi, rssi, 100, 10, 0, 0, 0, rssi, 100, 10],
+                    ),
+                ]
+            })
+            .collect();
+
+        MockTelemetry { frames, next: 0 }
+    }
+}
+
+impl Read for MockTelemetry {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(Duration::from_millis(100));
+
+        let frame = &self.frames[self.next];
+        self.next = (self.next + 1) % self.frames.len();
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery() -> Vec<u8> {
+        // 16.2V, 2.5A, 87% left
+        encode(BATTERY_SENSOR, &[0, 162, 0, 25, 0, 0, 0, 87])
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<CrsfFrame> {
+        let mut parser = CrsfParser::new();
+        parser.push_bytes(bytes);
+        std::iter::from_fn(|| parser.try_parse()).collect()
+    }
+
+    #[test]
+    fn battery_round_trips() {
+        assert_eq!(
+            parse(&battery()),
+            [CrsfFrame::Battery {
+                voltage: 16.2,
+                current: 2.5,
+                remaining: 87,
+            }]
+        );
+    }
+
+    #[test]
+    fn link_statistics_round_trip_on_the_active_antenna() {
+        let first = encode(LINK_STATISTICS, &[48, 71, 100, 10, 0, 0, 0, 48, 100, 10]);
+        let second = encode(LINK_STATISTICS, &[48, 71, 92, 10, 1, 0, 0, 71, 92, 10]);
+        assert_eq!(
+            parse(&[first, second].concat()),
+            [
+                CrsfFrame::LinkStatistics {
+                    rssi: -48,
+                    link_quality: 100,
+                },
+                CrsfFrame::LinkStatistics {
+                    rssi: -71,
+                    link_quality: 92,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        // Including bytes that look like an address with a silly length
+        let garbage = [0x00, 0xC8, 0xFF, 0x12, 0xEA, 0x01, 0x34];
+        let frames = parse(&[&garbage[..], &battery(), &garbage, &battery()].concat());
+        assert_eq!(frames.len(), 2);
+    }
+
+    #[test]
+    fn drops_a_bad_crc() {
+        let mut corrupt = battery();
+        corrupt[4] ^= 0x01;
+        assert!(parse(&corrupt).is_empty());
+        assert_eq!(parse(&[corrupt, battery()].concat()).len(), 1);
+    }
+
+    #[test]
+    fn skips_frames_of_other_types() {
+        let gps = encode(0x02, &[0; 15]);
+        assert_eq!(parse(&[gps, battery()].concat()).len(), 1);
+    }
+
+    #[test]
+    fn waits_for_a_frame_split_across_reads() {
+        let frame = battery();
+        let mut parser = CrsfParser::new();
+        for byte in &frame[..frame.len() - 1] {
+            parser.push_bytes(&[*byte]);
+            assert_eq!(parser.try_parse(), None);
+        }
+        parser.push_bytes(&frame[frame.len() - 1..]);
+        assert!(matches!(
+            parser.try_parse(),
+            Some(CrsfFrame::Battery { remaining: 87, .. })
+        ));
+    }
+}
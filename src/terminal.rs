@@ -0,0 +1,293 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::{mpsc::Sender, Arc};
+use std::time::Instant;
+
+use arc_swap::ArcSwap;
+use log::{error, warn};
+
+use crate::car::CarCommand;
+use crate::controls::{handle_input, Bindings, KEYBOARD};
+use crate::input::{
+    wait_readable, Button, ControllerInput, InputError, InputSource, REPEAT_INTERVAL,
+};
+use crate::link::LinkStats;
+use crate::recorder::{Recorder, RecordingInput};
+use crate::state_manager::{Slot, StateManager};
+
+// How far one key press moves the steering or throttle
+const STEP: f32 = 0.1;
+
+const HELP: &str = "w/s or up/down: throttle  a/d or left/right: steer  space: centre  \
+                    f/r: forward/reverse  +/-: arm/disarm  x: e-stop  p: profile  q: quit";
+
+/// Drives the car from the keyboard, for bench tests without a controller.
+/// Terminals only report key presses, so every press nudges the stick
+/// position instead of holding it, and the position is read out again while
+/// no keys are pressed.
+pub struct TerminalInput {
+    original: libc::termios,
+    horizontal: f32,
+    vertical: f32,
+    /// Keys read but not handed out yet, a read can carry several.
+    pending: VecDeque<Key>,
+    /// Set after a button press, so a reading with it let go comes next and
+    /// pressing the same key again counts.
+    release: bool,
+}
+
+/// One key press, out of what the terminal sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Quit,
+    Up,
+    Down,
+    Right,
+    Left,
+    Centre,
+    Press(Button),
+    /// Anything without a binding.
+    Other,
+}
+
+/// Splits what the terminal sent into key presses, escape sequence by
+/// escape sequence.
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut rest = bytes;
+
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        let key = match byte {
+            0x1b => match rest {
+                [b'[', sequence @ ..] => {
+                    // A CSI sequence runs up to its final byte
+                    let end = sequence
+                        .iter()
+                        .position(|b| (0x40..=0x7e).contains(b))
+                        .map_or(sequence.len(), |i| i + 1);
+                    let key = match sequence[..end] {
+                        [b'A'] => Key::Up,
+                        [b'B'] => Key::Down,
+                        [b'C'] => Key::Right,
+                        [b'D'] => Key::Left,
+                        _ => Key::Other,
+                    };
+                    rest = &sequence[end..];
+                    key
+                }
+                // Esc on its own
+                _ => Key::Quit,
+            },
+            b'q' | 0x03 | 0x04 => Key::Quit,
+            b'w' => Key::Up,
+            b's' => Key::Down,
+            b'd' => Key::Right,
+            b'a' => Key::Left,
+            b' ' => Key::Centre,
+            b'f' => Key::Press(Button::X),
+            b'r' => Key::Press(Button::B),
+            b'+' | b'=' => Key::Press(Button::Plus),
+            b'-' => Key::Press(Button::Minus),
+            b'x' => Key::Press(Button::Home),
+            b'p' => Key::Press(Button::Capture),
+            _ => Key::Other,
+        };
+        keys.push(key);
+    }
+
+    keys
+}
+
+impl TerminalInput {
+    /// Puts stdin into raw mode, it is restored when this is dropped.
+    pub fn new() -> io::Result<TerminalInput> {
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Keep output processing so that newlines still return the cursor
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(TerminalInput {
+            original,
+            horizontal: 0.0,
+            vertical: 0.0,
+            pending: VecDeque::new(),
+            release: false,
+        })
+    }
+
+    fn nudge(value: f32, by: f32) -> f32 {
+        (value + by).clamp(-1.0, 1.0)
+    }
+}
+
+impl Drop for TerminalInput {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+impl InputSource for TerminalInput {
+    fn read_input(&mut self) -> Result<ControllerInput, InputError> {
+        let mut buttons = HashSet::new();
+        if self.release {
+            self.release = false;
+        } else {
+            let device_error = |e: io::Error| InputError::Device(format!("stdin: {}", e));
+            // With no key pressed, the stick stays where it was
+            if self.pending.is_empty()
+                && wait_readable(libc::STDIN_FILENO, REPEAT_INTERVAL).map_err(device_error)?
+            {
+                let mut buf = [0u8; 64];
+                let len = io::stdin().read(&mut buf).map_err(device_error)?;
+                if len == 0 {
+                    return Err(InputError::Closed);
+                }
+                self.pending.extend(parse_keys(&buf[..len]));
+            }
+
+            match self.pending.pop_front().unwrap_or(Key::Other) {
+                // q, Esc, Ctrl-C and Ctrl-D all quit, as does EOF
+                Key::Quit => return Err(InputError::Closed),
+                Key::Up => self.vertical = Self::nudge(self.vertical, STEP),
+                Key::Down => self.vertical = Self::nudge(self.vertical, -STEP),
+                Key::Right => self.horizontal = Self::nudge(self.horizontal, STEP),
+                Key::Left => self.horizontal = Self::nudge(self.horizontal, -STEP),
+                Key::Centre => {
+                    self.horizontal = 0.0;
+                    self.vertical = 0.0;
+                }
+                Key::Press(button) => {
+                    buttons.insert(button);
+                    self.release = true;
+                }
+                Key::Other => {}
+            }
+        }
+
+        Ok(ControllerInput {
+            device: "terminal".to_owned(),
+            timestamp: Instant::now(),
+            horizontal: self.horizontal,
+            vertical: self.vertical,
+            buttons,
+            motion: Vec::new(),
+        })
+    }
+
+    fn bindings(&self) -> &'static Bindings {
+        &KEYBOARD
+    }
+}
+
+/// The status line, with the channels the car thread last sent rather than
+/// what the keys asked for, so the profile, battery and trainer limits show.
+fn print_status(state: &StateManager, input: &ControllerInput, stats: &LinkStats) {
+    let channels = match stats.channels {
+        Some(channels) => channels
+            .iter()
+            .map(|c| format!("{:4}", c))
+            .collect::<Vec<String>>()
+            .join(" "),
+        None => "nothing sent yet".to_owned(),
+    };
+
+    print!(
+        "\r\x1b[2K{:12} steer {:+.1} throttle {:+.1} | {} | {}",
+        format!("{:?}", state.l.mode),
+        input.horizontal,
+        input.vertical,
+        state.telemetry,
+        channels
+    );
+    let _ = io::stdout().flush();
+}
+
+/// Runs the keyboard as the left controller until the user quits, with a
+/// status line showing the channels last sent to the car.
+pub fn run_terminal(
+    state_store: Arc<ArcSwap<StateManager>>,
+    link_stats: Arc<ArcSwap<LinkStats>>,
+    car_tx: Sender<CarCommand>,
+    recorder: Option<Recorder>,
+) {
+    let terminal = match TerminalInput::new() {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            error!("Failed to put the terminal in raw mode: {}", e);
+            return;
+        }
+    };
+
+    println!("{}", HELP);
+
+    let mut terminal: Box<dyn InputSource> = match recorder {
+        Some(recorder) => Box::new(RecordingInput::new(terminal, Slot::Left, recorder)),
+        None => Box::new(terminal),
+    };
+
+    let bindings = terminal.bindings();
+
+    loop {
+        match terminal.read_input() {
+            Ok(input) => {
+                if let Some(command) = handle_input(&state_store, Slot::Left, &input, bindings) {
+                    car_tx.send(command).unwrap();
+                }
+
+                print_status(&state_store.load(), &input, &link_stats.load());
+            }
+            Err(InputError::Closed) => break,
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_keys() {
+        assert_eq!(parse_keys(b"w"), [Key::Up]);
+        assert_eq!(parse_keys(b"\x1b[D"), [Key::Left]);
+        assert_eq!(parse_keys(b"\x1b"), [Key::Quit]);
+        assert_eq!(parse_keys(b"x"), [Key::Press(Button::Home)]);
+    }
+
+    #[test]
+    fn several_keys_in_one_read() {
+        assert_eq!(
+            parse_keys(b"ww\x1b[C+f"),
+            [
+                Key::Up,
+                Key::Up,
+                Key::Right,
+                Key::Press(Button::Plus),
+                Key::Press(Button::X)
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_sequences_are_skipped_whole() {
+        // F5, then Delete
+        assert_eq!(
+            parse_keys(b"\x1b[15~\x1b[3~s"),
+            [Key::Other, Key::Other, Key::Down]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use log::info;
+
+use crate::controls::{Bindings, Tap};
+use crate::feedback::{FeedbackEvent, PlayerLights};
+use crate::input::{ControllerInput, InputError, InputSource, MotionSample};
+
+// JoyCons sample their IMU every 5ms
+const SAMPLE_PERIOD: f32 = 0.005;
+// Samples averaged to find the gyro bias, about a second's worth
+const CALIBRATION_SAMPLES: usize = 200;
+// Any more than this away from 1g and the controller wasn't at rest
+const REST_TOLERANCE: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TiltConfig {
+    /// Tilt in degrees that gives full steering lock.
+    pub max_tilt: f32,
+    /// Tilt in degrees either side of centre that is ignored.
+    pub deadzone: f32,
+    /// How much the filter trusts the gyro over the accelerometer, 0.0..1.0.
+    pub gyro_weight: f32,
+    /// Flips the steering direction, for controllers held the other way round.
+    pub invert: bool,
+}
+
+impl Default for TiltConfig {
+    fn default() -> TiltConfig {
+        TiltConfig {
+            max_tilt: 45.0,
+            deadzone: 3.0,
+            gyro_weight: 0.98,
+            invert: false,
+        }
+    }
+}
+
+enum Phase {
+    Calibrating {
+        samples: usize,
+        gyro_sum: f32,
+        accel_sum: [f32; 2],
+    },
+    Tracking {
+        gyro_bias: f32,
+        // Direction of gravity when calibrated, that's what "straight" means
+        reference: f32,
+        angle: f32,
+    },
+}
+
+const CALIBRATING: Phase = Phase::Calibrating {
+    samples: 0,
+    gyro_sum: 0.0,
+    accel_sum: [0.0, 0.0],
+};
+
+/// Turns a controller held like a steering wheel into a steering position.
+/// The wheel's axis is the controller's Z axis, straight out of its face.
+pub struct TiltSteering {
+    config: TiltConfig,
+    phase: Phase,
+}
+
+/// Difference between two angles in degrees, wrapped to -180..180.
+fn angle_between(angle: f32, reference: f32) -> f32 {
+    (angle - reference + 540.0).rem_euclid(360.0) - 180.0
+}
+
+fn roll(sample: &MotionSample) -> f32 {
+    sample.accel[1].atan2(sample.accel[0]).to_degrees()
+}
+
+impl TiltSteering {
+    pub fn new(config: TiltConfig) -> TiltSteering {
+        TiltSteering {
+            config,
+            phase: CALIBRATING,
+        }
+    }
+
+    /// Starts over, the controller has to be held still and straight for about a second.
+    pub fn recalibrate(&mut self) {
+        self.phase = CALIBRATING;
+    }
+
+    /// Feeds new IMU samples through the filter. Returns the steering position
+    /// (-1.0..1.0) once calibration has finished.
+    pub fn update(&mut self, samples: &[MotionSample]) -> Option<f32> {
+        for sample in samples {
+            self.phase = match self.phase {
+                Phase::Calibrating {
+                    samples,
+                    gyro_sum,
+                    accel_sum,
+                } => {
+                    let magnitude = sample.accel.iter().map(|a| a * a).sum::<f32>().sqrt();
+
+                    if (magnitude - 1.0).abs() > REST_TOLERANCE {
+                        CALIBRATING
+                    } else if samples + 1 < CALIBRATION_SAMPLES {
+                        Phase::Calibrating {
+                            samples: samples + 1,
+                            gyro_sum: gyro_sum + sample.gyro[2],
+                            accel_sum: [
+                                accel_sum[0] + sample.accel[0],
+                                accel_sum[1] + sample.accel[1],
+                            ],
+                        }
+                    } else {
+                        info!("Tilt steering calibrated");
+                        Phase::Tracking {
+                            gyro_bias: (gyro_sum + sample.gyro[2]) / CALIBRATION_SAMPLES as f32,
+                            reference: (accel_sum[1] + sample.accel[1])
+                                .atan2(accel_sum[0] + sample.accel[0])
+                                .to_degrees(),
+                            angle: 0.0,
+                        }
+                    }
+                }
+                Phase::Tracking {
+                    gyro_bias,
+                    reference,
+                    angle,
+                } => {
+                    // Complementary filter: the gyro is smooth but drifts, the
+                    // accelerometer is noisy but always knows where down is.
+                    // Turning the controller one way turns gravity the other
+                    // way as the controller sees it, so that angle is negated
+                    let gyro_angle = angle + (sample.gyro[2] - gyro_bias) * SAMPLE_PERIOD;
+                    let accel_angle = angle_between(reference, roll(sample));
+                    let weight = self.config.gyro_weight;
+
+                    Phase::Tracking {
+                        gyro_bias,
+                        reference,
+                        angle: weight * gyro_angle + (1.0 - weight) * accel_angle,
+                    }
+                }
+            };
+        }
+
+        match self.phase {
+            Phase::Tracking { angle, .. } => Some(self.steering(angle)),
+            Phase::Calibrating { .. } => None,
+        }
+    }
+
+    fn steering(&self, angle: f32) -> f32 {
+        let TiltConfig {
+            max_tilt,
+            deadzone,
+            invert,
+            ..
+        } = self.config;
+
+        if angle.abs() <= deadzone || max_tilt <= deadzone {
+            return 0.0;
+        }
+
+        let steering = ((angle.abs() - deadzone) / (max_tilt - deadzone)).min(1.0) * angle.signum();
+        if invert {
+            -steering
+        } else {
+            steering
+        }
+    }
+}
+
+/// Wraps a controller so that tilting it steers instead of the stick.
+pub struct TiltInput<S> {
+    inner: S,
+    tilt: TiltSteering,
+    /// Recalibrating counts when tapped, it shares a button with emergency
+    /// stop.
+    recalibrate: Tap,
+}
+
+impl<S: InputSource> TiltInput<S> {
+    pub fn new(inner: S, config: TiltConfig) -> TiltInput<S> {
+        info!("Hold the controller still to calibrate tilt steering");
+        TiltInput {
+            inner,
+            tilt: TiltSteering::new(config),
+            recalibrate: Tap::default(),
+        }
+    }
+}
+
+impl<S: InputSource> InputSource for TiltInput<S> {
+    fn read_input(&mut self) -> Result<ControllerInput, InputError> {
+        let mut input = self.inner.read_input()?;
+
+        let bindings = self.inner.bindings();
+        if self
+            .recalibrate
+            .update(&input.buttons, bindings.recalibrate)
+        {
+            info!("{}: recalibrating tilt steering, hold still", input.device);
+            self.tilt.recalibrate();
+        }
+
+        // Keep the wheels straight until we know which way is straight
+        input.horizontal = self.tilt.update(&input.motion).unwrap_or(0.0);
+
+        Ok(input)
+    }
+
+    fn bindings(&self) -> &'static Bindings {
+        self.inner.bindings()
+    }
+
+    fn feedback(&mut self, event: FeedbackEvent) {
+        self.inner.feedback(event)
+    }
+
+    fn indicate(&mut self, lights: PlayerLights) {
+        self.inner.indicate(lights)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A controller turned `angle` degrees about its Z axis, turning at
+    /// `rate` degrees per second. Gravity starts out along X.
+    fn sample(angle: f32, rate: f32) -> MotionSample {
+        let radians = angle.to_radians();
+        MotionSample {
+            accel: [radians.cos(), -radians.sin(), 0.0],
+            gyro: [0.0, 0.0, rate],
+        }
+    }
+
+    fn calibrated() -> TiltSteering {
+        let mut tilt = TiltSteering::new(TiltConfig::default());
+        let still = vec![sample(0.0, 0.0); CALIBRATION_SAMPLES];
+        assert_eq!(tilt.update(&still), Some(0.0));
+        tilt
+    }
+
+    #[test]
+    fn steering_sign_holds_while_turning_and_after() {
+        for rate in [60.0, -60.0] {
+            let mut tilt = calibrated();
+            let mut angle = 0.0;
+
+            // Turn for half a second, then hold the tilt for two
+            for step in 0..500 {
+                let turning = step < 100;
+                if turning {
+                    angle += rate * SAMPLE_PERIOD;
+                }
+                let steering = tilt
+                    .update(&[sample(angle, if turning { rate } else { 0.0 })])
+                    .unwrap();
+                assert!(
+                    steering == 0.0 || steering.signum() == rate.signum(),
+                    "steering {} at step {} turning at {}",
+                    steering,
+                    step,
+                    rate
+                );
+            }
+
+            // 30 degrees of 45, past the 3 degree dead zone
+            let steering = tilt.update(&[sample(angle, 0.0)]).unwrap();
+            assert!((steering.abs() - 27.0 / 42.0).abs() < 0.01, "{}", steering);
+        }
+    }
+
+    #[test]
+    fn accelerometer_alone_settles_on_the_tilt() {
+        let mut tilt = calibrated();
+        let held = vec![sample(-20.0, 0.0); 1000];
+        let steering = tilt.update(&held).unwrap();
+        assert!((steering + 17.0 / 42.0).abs() < 0.01, "{}", steering);
+    }
+}
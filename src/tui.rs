@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+
+use crate::controls::Bindings;
+use crate::feedback::{FeedbackEvent, PlayerLights};
+use crate::input::{ControllerInput, InputError, InputSource};
+use crate::link::LinkStats;
+use crate::state_manager::{Slot, StateManager};
+use crate::utils::{map_axis, mix_joycon_states};
+
+// How often the dashboard is redrawn, and the frame rate worked out
+const REFRESH: Duration = Duration::from_millis(100);
+// A controller that hasn't sent anything for this long shows as lost
+const CONNECTION_TIMEOUT: Duration = Duration::from_millis(500);
+const EVENTS_SHOWN: usize = 10;
+
+/// What one controller sent last.
+struct ControllerFeed {
+    device: String,
+    bindings: &'static str,
+    horizontal: f32,
+    vertical: f32,
+    at: Instant,
+    error: Option<String>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Feed {
+    left: Option<ControllerFeed>,
+    right: Option<ControllerFeed>,
+    events: VecDeque<String>,
+}
+
+impl Feed {
+    fn controller_mut(&mut self, slot: Slot) -> &mut Option<ControllerFeed> {
+        match slot {
+            Slot::Left => &mut self.left,
+            Slot::Right => &mut self.right,
+        }
+    }
+}
+
+/// Collects what the controllers and the log are doing for a live dashboard,
+/// which replaces the scrolling log on the terminal.
+#[derive(Clone, Default)]
+pub struct Dashboard {
+    feed: Arc<Mutex<Feed>>,
+}
+
+impl Dashboard {
+    pub fn new() -> Dashboard {
+        Dashboard::default()
+    }
+
+    /// Starts redrawing the dashboard from `state_store`, `link_stats` and
+    /// whatever is fed in.
+    pub fn start(
+        &self,
+        state_store: Arc<ArcSwap<StateManager>>,
+        link_stats: Arc<ArcSwap<LinkStats>>,
+    ) {
+        let feed = self.feed.clone();
+
+        thread::spawn(move || {
+            // Clear once, every redraw then writes over the last one
+            print!("\x1b[2J");
+            let mut last_refresh = Instant::now();
+            let mut frames_at_refresh = 0;
+
+            loop {
+                thread::sleep(REFRESH);
+
+                let stats = link_stats.load();
+                let elapsed = last_refresh.elapsed();
+                let frame_rate = (stats.frames - frames_at_refresh) as f32 / elapsed.as_secs_f32();
+                frames_at_refresh = stats.frames;
+                last_refresh = Instant::now();
+
+                let screen = render(
+                    &state_store.load(),
+                    &stats,
+                    &feed.lock().unwrap(),
+                    frame_rate,
+                );
+
+                let mut stdout = io::stdout().lock();
+                let _ = write!(stdout, "\x1b[H{}\x1b[J", screen);
+                let _ = stdout.flush();
+            }
+        });
+    }
+
+    fn input(&self, slot: Slot, input: &ControllerInput, bindings: &'static Bindings) {
+        *self.feed.lock().unwrap().controller_mut(slot) = Some(ControllerFeed {
+            device: input.device.clone(),
+            bindings: bindings.name,
+            horizontal: input.horizontal,
+            vertical: input.vertical,
+            at: Instant::now(),
+            error: None,
+            closed: false,
+        });
+    }
+
+    fn input_error(&self, slot: Slot, error: &InputError) {
+        if let Some(controller) = self.feed.lock().unwrap().controller_mut(slot) {
+            match error {
+                InputError::Closed => controller.closed = true,
+                InputError::Device(message) => controller.error = Some(message.clone()),
+            }
+        }
+    }
+
+    /// Adds a line to the recent events, the log ends up here.
+    pub fn event(&self, line: String) {
+        let mut feed = self.feed.lock().unwrap();
+        if feed.events.len() == EVENTS_SHOWN {
+            feed.events.pop_front();
+        }
+        feed.events.push_back(line);
+    }
+}
+
+fn controller_line(state: &StateManager, slot: Slot, feed: Option<&ControllerFeed>) -> String {
+    let mode = state.slot(slot).mode;
+    let Some(feed) = feed else {
+        return format!(
+            "  {:6} {:28} {:10} {:?}",
+            format!("{:?}", slot),
+            "-",
+            "none",
+            mode
+        );
+    };
+
+    let connection = if feed.closed {
+        "closed"
+    } else if feed.at.elapsed() > CONNECTION_TIMEOUT {
+        "lost"
+    } else {
+        "connected"
+    };
+    let mut line = format!(
+        "  {:6} {:28} {:10} {:13} {:+.2} {:+.2}  {:4} {:4}",
+        format!("{:?}", slot),
+        format!("{} ({})", feed.device, feed.bindings),
+        connection,
+        format!("{:?}", mode),
+        feed.horizontal,
+        feed.vertical,
+        map_axis(feed.horizontal, mode.is_forward()),
+        map_axis(feed.vertical, false),
+    );
+    if state.in_control(slot) {
+        line.push_str("  driving");
+    }
+    if let Some(error) = &feed.error {
+        let _ = write!(line, "  ({})", error);
+    }
+    line
+}
+
+fn render(state: &StateManager, stats: &LinkStats, feed: &Feed, frame_rate: f32) -> String {
+    let (forward, armed) = mix_joycon_states(state);
+    let owner = match state.arbiter.owner() {
+        Some(slot) => format!("{:?} driving", slot),
+        None => "nobody driving".to_owned(),
+    };
+
+    let mut lines = vec![
+        format!(
+            "glorb-control | {:?} | {:?} profile | {}",
+            state.arbiter.policy(),
+            state.profile,
+            owner
+        ),
+        String::new(),
+        format!(
+            "  {:6} {:28} {:10} {:13} {:11}  {:9}",
+            "Slot", "Device", "Status", "Mode", "Raw", "Mapped"
+        ),
+        controller_line(state, Slot::Left, feed.left.as_ref()),
+        controller_line(state, Slot::Right, feed.right.as_ref()),
+        String::new(),
+    ];
+
+    match (stats.command, stats.channels) {
+        (Some((horizontal, vertical)), Some(channels)) => {
+            lines.push(format!(
+                "  Car    steer {} throttle {} | {} {} | {:5.1} fps | {} failed | link {}",
+                horizontal,
+                vertical,
+                if forward { "forward" } else { "reverse" },
+                if armed { "armed" } else { "disarmed" },
+                frame_rate,
+                stats.failures,
+                stats.error.as_deref().unwrap_or("ok")
+            ));
+            let channels = channels
+                .iter()
+                .map(|c| format!("{:4}", c))
+                .collect::<Vec<String>>()
+                .join(" ");
+            lines.push(format!("  SBUS   {}", channels));
+        }
+        _ => lines.push("  Car    nothing sent yet".to_owned()),
+    }
+    lines.push(format!(
+        "  Car    {} | battery {:?}",
+        state.telemetry,
+        state.battery.level()
+    ));
+    lines.push(format!("  Delay  {}", stats.latency));
+    lines.push(String::new());
+
+    lines.push("Recent events".to_owned());
+    lines.extend(feed.events.iter().map(|event| format!("  {}", event)));
+
+    // Clear what is left of each line from the last redraw
+    lines.join("\x1b[K\n") + "\x1b[K"
+}
+
+/// Passes `inner`'s readings through, showing them on the dashboard.
+pub struct DashboardInput<S> {
+    inner: S,
+    slot: Slot,
+    dashboard: Dashboard,
+}
+
+impl<S: InputSource> DashboardInput<S> {
+    pub fn new(inner: S, slot: Slot, dashboard: Dashboard) -> DashboardInput<S> {
+        DashboardInput {
+            inner,
+            slot,
+            dashboard,
+        }
+    }
+}
+
+impl<S: InputSource> InputSource for DashboardInput<S> {
+    fn read_input(&mut self) -> Result<ControllerInput, InputError> {
+        match self.inner.read_input() {
+            Ok(input) => {
+                self.dashboard
+                    .input(self.slot, &input, self.inner.bindings());
+                Ok(input)
+            }
+            Err(e) => {
+                self.dashboard.input_error(self.slot, &e);
+                Err(e)
+            }
+        }
+    }
+
+    fn bindings(&self) -> &'static Bindings {
+        self.inner.bindings()
+    }
+
+    fn feedback(&mut self, event: FeedbackEvent) {
+        self.inner.feedback(event)
+    }
+
+    fn indicate(&mut self, lights: PlayerLights) {
+        self.inner.indicate(lights)
+    }
+}